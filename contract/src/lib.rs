@@ -1,21 +1,24 @@
 use near_contract_standards::fungible_token::{
     core::FungibleTokenCore,
     metadata::{FungibleTokenMetadata, FungibleTokenMetadataProvider, FT_METADATA_SPEC},
+    receiver::FungibleTokenReceiver,
     resolver::FungibleTokenResolver,
     FungibleToken,
 };
-use near_sdk::json_types::U128;
+use near_sdk::json_types::{Base64VecU8, U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::{json, Value};
 use near_sdk::{
     borsh::{self, BorshDeserialize, BorshSerialize},
     PromiseOrValue,
 };
 use near_sdk::{
-    collections::{LazyOption, LookupMap},
+    collections::{LazyOption, LookupMap, LookupSet, Vector},
     PanicOnDefault,
 };
 use near_sdk::{env, log, near_bindgen, require, AccountId, Balance, BorshStorageKey, Promise};
-use near_sdk::{ext_contract, PromiseResult};
+use near_sdk::{ext_contract, CurveType, PromiseResult, PublicKey};
+use std::convert::TryFrom;
 
 /// Storage keys
 #[derive(BorshSerialize, BorshStorageKey)]
@@ -26,6 +29,34 @@ enum StorageKey {
     Subscriptions,
     Outputs,
     Inputs,
+    Claimable,
+    LastCreate,
+    Frozen,
+    Templates,
+    EndTimeApprovals,
+    AccountFlows,
+    TokenMetadata,
+    AllowedDestinations,
+    EnforceAllowlist,
+    MaxIncomingFlow,
+    SettlementNonces,
+    FlowHistory,
+    Requests,
+    SourceRequests,
+    AcknowledgmentRequired,
+    RelayKeys,
+    RelayNonces,
+    FeesByDay,
+    Holders,
+    HolderSet,
+    NotificationTargets,
+    Escrows,
+    Passthroughs,
+    Groups,
+    GroupOwners,
+    IncomingSplits,
+    SettlementCredits,
+    LockedReserves,
 }
 
 /// An index for a subscription
@@ -34,6 +65,39 @@ type SubscriptionIndex = u64;
 type YoctosPerSecond = u128;
 /// Seconds
 type Seconds = u64;
+/// Basis points, where 10000 is the whole
+type BasisPoints = u16;
+/// An index for a request for a stream
+type RequestId = u64;
+/// An id for a group of subscriptions
+type GroupId = u64;
+
+/// An amount of yoctos with arithmetic that fails on overflow rather than clamping
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Default)]
+pub struct Yoctos(pub Balance);
+
+impl Yoctos {
+    /// Add the amount, `None` on overflow
+    pub fn checked_add(self, other: Yoctos) -> Option<Yoctos> {
+        self.0.checked_add(other.0).map(Yoctos)
+    }
+
+    /// Subtract the amount, `None` when it is greater than this
+    pub fn checked_sub(self, other: Yoctos) -> Option<Yoctos> {
+        self.0.checked_sub(other.0).map(Yoctos)
+    }
+}
+
+/// A rate of yoctos per second with arithmetic that fails on overflow rather than clamping
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Default)]
+pub struct Flow(pub YoctosPerSecond);
+
+impl Flow {
+    /// The amount streamed over the seconds, `None` on overflow
+    pub fn checked_over(self, seconds: Seconds) -> Option<Yoctos> {
+        self.0.checked_mul(seconds as u128).map(Yoctos)
+    }
+}
 
 /// A Subscription which has a source account which will stream at rate from timestamp to the source account
 #[near_bindgen]
@@ -48,17 +112,432 @@ pub struct Subscription {
     flow: YoctosPerSecond,
     /// The start time of the stream
     timestamp: Seconds,
+    /// The time the stream ends, if it is bounded
+    end_timestamp: Option<Seconds>,
+    /// The time from which the stream is paid, nothing accrues before this
+    paid_from: Seconds,
+    /// The time the subscription was created
+    created_at: Seconds,
+    /// A single payment made at `unlock_at` instead of streaming, zero for a stream
+    lump_sum: Balance,
+    /// The time the lump sum is paid
+    unlock_at: Seconds,
+    /// Reserve of the source held for the subscription, released on removal
+    locked_reserve: Balance,
+    /// If settlement is claimed by the destination rather than pushed into its balance
+    pull: bool,
+    /// Total settled over the life of the subscription
+    total_settled: Balance,
+    /// If the destination has acknowledged the subscription, nothing accrues until it has
+    active: bool,
+    /// The most the subscription settles in total, it completes once `total_settled` reaches this
+    cap: Option<Balance>,
+    /// An oracle price condition the subscription only accrues while holding
+    condition: Option<OracleCondition>,
+    /// Rate of stream in yoctos per `FLOW_SCALE` seconds, for streams slower than a yocto a
+    /// second.  `flow` is this truncated to whole yoctos a second.
+    scaled_flow: Option<u128>,
+    /// Fraction of a yocto, in yoctos per `FLOW_SCALE`, accrued but not yet settled
+    scaled_remainder: u128,
+    /// Account notified of each settlement, in place of the destination's notification target
+    notify: Option<AccountId>,
+    /// Group the source has placed the subscription in, removed together by `remove_group`
+    group_id: Option<GroupId>,
+    /// The contract's `frozen_offset` when the subscription was stored, its times are moved
+    /// forward by however much that has grown since
+    frozen_offset: Seconds,
 }
 
 impl Subscription {
-    /// Settle the subscription returning the amount to settle
-    pub fn settle(&mut self) -> Balance {
-        let timestamp = env::block_timestamp();
-        let time_spent = timestamp.saturating_sub(self.timestamp);
-        let amount = (time_spent as u128).saturating_mul(self.flow);
+    /// Settle the subscription up to timestamp returning the amount to settle.  A timestamp
+    /// before the last settlement is a clock gone backwards: it is logged with a `clock_anomaly`
+    /// event and nothing settles, the subscription keeps its later timestamp so time isn't
+    /// accrued twice once the clock catches up.
+    pub fn settle(&mut self, timestamp: Seconds) -> Balance {
+        if timestamp < self.timestamp {
+            emit_event(
+                "clock_anomaly",
+                json!({ "timestamp": timestamp, "settled_at": self.timestamp }),
+            );
+            return 0;
+        }
+        let (amount, scaled_remainder) = self.accrual(timestamp);
+        self.scaled_remainder = scaled_remainder;
         self.timestamp = timestamp;
         amount
     }
+
+    /// Move the times of the subscription forward by the time accrual has been frozen for since
+    /// it was stored, so that nothing accrues for the frozen window
+    fn thaw(&mut self, frozen_offset: Seconds) {
+        let frozen_duration = frozen_offset.saturating_sub(self.frozen_offset);
+        self.timestamp = self.timestamp.saturating_add(frozen_duration);
+        self.paid_from = self.paid_from.saturating_add(frozen_duration);
+        if self.lump_sum > 0 {
+            self.unlock_at = self.unlock_at.saturating_add(frozen_duration);
+        }
+        self.end_timestamp = self
+            .end_timestamp
+            .map(|end_timestamp| end_timestamp.saturating_add(frozen_duration));
+        self.frozen_offset = self.frozen_offset.max(frozen_offset);
+    }
+
+    /// If the subscription doesn't stream at a constant rate from its last settlement, because it
+    /// ends, hasn't started to be paid, is a lump sum or is waiting to be acknowledged
+    pub fn is_variable(&self) -> bool {
+        self.end_timestamp.is_some()
+            || self.cap.is_some()
+            || self.condition.is_some()
+            || self.scaled_flow.is_some()
+            || self.paid_from > self.timestamp
+            || self.lump_sum > 0
+            || !self.active
+    }
+
+    /// The time the source may remove the subscription, once half of the reserve window it
+    /// locked at creation has elapsed
+    pub fn reserve_unlocked_at(&self) -> Seconds {
+        let reserve_window = self
+            .locked_reserve
+            .checked_div(self.flow)
+            .unwrap_or_default() as Seconds;
+        self.created_at.saturating_add(reserve_window / 2)
+    }
+
+    /// The role of the account in the subscription, either source or destination
+    pub fn role(&self, account_id: &AccountId) -> Option<&'static str> {
+        if &self.source == account_id {
+            Some("source")
+        } else if &self.destination == account_id {
+            Some("destination")
+        } else {
+            None
+        }
+    }
+
+    /// The amount accrued since the last settlement up to timestamp, bounded by the end time.  A
+    /// lump sum accrues in full once unlocked.  Nothing accrues until the subscription is active.
+    pub fn accrued(&self, timestamp: Seconds) -> Balance {
+        self.accrual(timestamp).0
+    }
+
+    /// The amount accrued up to timestamp with the fraction of a yocto left over of a scaled flow
+    fn accrual(&self, timestamp: Seconds) -> (Balance, u128) {
+        if !self.active
+            || self
+                .condition
+                .as_ref()
+                .is_some_and(|condition| !condition.holds)
+        {
+            return (0, self.scaled_remainder);
+        }
+        if self.lump_sum > 0 {
+            return if self.timestamp < self.unlock_at && timestamp >= self.unlock_at {
+                (self.lump_sum, 0)
+            } else {
+                (0, 0)
+            };
+        }
+        let timestamp = self
+            .end_timestamp
+            .map_or(timestamp, |end_timestamp| timestamp.min(end_timestamp));
+        let time_spent = timestamp.saturating_sub(self.timestamp.max(self.paid_from));
+        let (accrued, scaled_remainder) = match self.scaled_flow {
+            Some(scaled_flow) => {
+                let scaled = (time_spent as u128)
+                    .saturating_mul(scaled_flow)
+                    .saturating_add(self.scaled_remainder);
+                (scaled / FLOW_SCALE, scaled % FLOW_SCALE)
+            }
+            None => {
+                let accrued = Flow(self.flow)
+                    .checked_over(time_spent)
+                    .expect("accrual overflows");
+                (accrued.0, 0)
+            }
+        };
+        let accrued = self.cap.map_or(accrued, |cap| {
+            accrued.min(cap.saturating_sub(self.total_settled))
+        });
+        (accrued, scaled_remainder)
+    }
+
+    /// The amount the stream pays for the seconds, dropping any fraction of a yocto
+    fn paid_for(&self, seconds: Seconds) -> Balance {
+        match self.scaled_flow {
+            Some(scaled_flow) => (seconds as u128).saturating_mul(scaled_flow) / FLOW_SCALE,
+            None => (seconds as u128).saturating_mul(self.flow),
+        }
+    }
+
+    /// The whole seconds of stream the amount pays for
+    fn time_paid_by(&self, amount: Balance) -> Seconds {
+        match self.scaled_flow {
+            Some(scaled_flow) => amount
+                .saturating_mul(FLOW_SCALE)
+                .checked_div(scaled_flow)
+                .unwrap_or_default() as Seconds,
+            None => amount.checked_div(self.flow).unwrap_or_default() as Seconds,
+        }
+    }
+
+    /// The time a capped subscription will have accrued the rest of its cap, `None` if it isn't
+    /// capped or streams nothing so never completes
+    pub fn completion_time(&self) -> Option<Seconds> {
+        let remaining = self.cap?.saturating_sub(self.total_settled);
+        if self.flow == 0 {
+            return None;
+        }
+        let time_left = remaining
+            .checked_add(self.flow - 1)
+            .and_then(|remaining| remaining.checked_div(self.flow))
+            .unwrap_or_default() as Seconds;
+        Some(self.timestamp.max(self.paid_from).saturating_add(time_left))
+    }
+
+    /// If the subscription has been acknowledged and hasn't ended by the timestamp
+    pub fn is_active(&self, timestamp: Seconds) -> bool {
+        self.active
+            && self
+                .end_timestamp
+                .is_none_or(|end_timestamp| timestamp < end_timestamp)
+    }
+
+    /// If a capped subscription has settled all of its cap
+    pub fn is_complete(&self) -> bool {
+        self.cap.is_some_and(|cap| self.total_settled >= cap)
+    }
+}
+
+/// Aggregate flows of an account so its balance can be calculated without visiting each of its
+/// subscriptions.  Subscriptions which don't stream at a constant rate are listed separately.
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct AccountFlow {
+    /// Total flow of constant subscriptions where the account is destination
+    incoming_flow: YoctosPerSecond,
+    /// Total flow of constant subscriptions where the account is source
+    outgoing_flow: YoctosPerSecond,
+    /// Unsettled incoming amount at `last_settled_at`
+    incoming_accrued: Balance,
+    /// Unsettled outgoing amount at `last_settled_at`
+    outgoing_accrued: Balance,
+    /// The time the accrued amounts were last brought up to date
+    last_settled_at: Seconds,
+    /// Subscriptions of the account which don't stream at a constant rate
+    variable_subscriptions: Vec<SubscriptionIndex>,
+    /// The contract's `frozen_offset` when the flows were stored
+    frozen_offset: Seconds,
+}
+
+impl AccountFlow {
+    /// Move `last_settled_at` forward by the time accrual has been frozen for since the flows
+    /// were stored, so that nothing accrues for the frozen window
+    fn thaw(&mut self, frozen_offset: Seconds) {
+        let frozen_duration = frozen_offset.saturating_sub(self.frozen_offset);
+        self.last_settled_at = self.last_settled_at.saturating_add(frozen_duration);
+        self.frozen_offset = frozen_offset;
+    }
+
+    /// Bring the accrued amounts up to the timestamp, never moving back if the clock has
+    fn checkpoint(&mut self, timestamp: Seconds) {
+        let (incoming_accrued, outgoing_accrued) = self.accrued(timestamp);
+        self.incoming_accrued = incoming_accrued;
+        self.outgoing_accrued = outgoing_accrued;
+        self.last_settled_at = self.last_settled_at.max(timestamp);
+    }
+
+    /// The unsettled incoming and outgoing amounts of constant subscriptions at the timestamp
+    fn accrued(&self, timestamp: Seconds) -> (Balance, Balance) {
+        let time_spent = timestamp.saturating_sub(self.last_settled_at) as u128;
+        (
+            self.incoming_accrued
+                .saturating_add(time_spent.saturating_mul(self.incoming_flow)),
+            self.outgoing_accrued
+                .saturating_add(time_spent.saturating_mul(self.outgoing_flow)),
+        )
+    }
+}
+
+/// The period an amount is streamed over, a month being 30 days
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(crate = "near_sdk::serde")]
+pub enum FlowUnit {
+    PerSecond,
+    PerHour,
+    PerDay,
+    PerMonth,
+}
+
+impl FlowUnit {
+    /// Seconds in the period
+    pub fn seconds(&self) -> Seconds {
+        match self {
+            Self::PerSecond => 1,
+            Self::PerHour => 60 * 60,
+            Self::PerDay => SECONDS_PER_DAY,
+            Self::PerMonth => SECONDS_PER_MONTH,
+        }
+    }
+
+    /// The flow per second which streams the amount over the period, truncating any fraction
+    /// of a yocto
+    pub fn to_flow(&self, amount: Balance) -> YoctosPerSecond {
+        amount / self.seconds() as u128
+    }
+}
+
+/// How a remainder of integer division is handled, such as the fraction of a yocto in a fee
+#[derive(
+    Serialize, Deserialize, BorshDeserialize, BorshSerialize, Debug, PartialEq, Clone, Copy,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum RoundingMode {
+    /// Drop the remainder
+    Floor,
+    /// Round up when the remainder is half or more
+    HalfUp,
+}
+
+impl RoundingMode {
+    /// Divide the numerator by the denominator, rounding the remainder
+    pub fn divide(&self, numerator: u128, denominator: u128) -> u128 {
+        let quotient = numerator / denominator;
+        match self {
+            Self::Floor => quotient,
+            Self::HalfUp if numerator % denominator >= denominator - denominator / 2 => {
+                quotient + 1
+            }
+            Self::HalfUp => quotient,
+        }
+    }
+}
+
+/// A comparison of the price reported by an oracle with a threshold
+#[derive(
+    Serialize, Deserialize, BorshDeserialize, BorshSerialize, Debug, PartialEq, Clone, Copy,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum PriceCondition {
+    /// The price is above the threshold
+    Above(U128),
+    /// The price is below the threshold
+    Below(U128),
+}
+
+impl PriceCondition {
+    /// If the price meets the condition
+    pub fn holds(&self, price: Balance) -> bool {
+        match self {
+            Self::Above(threshold) => price > threshold.0,
+            Self::Below(threshold) => price < threshold.0,
+        }
+    }
+}
+
+/// A price condition of a subscription with the oracle reporting the price
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OracleCondition {
+    /// The contract reporting the price with `get_price`
+    oracle: AccountId,
+    /// The condition on the price
+    condition: PriceCondition,
+    /// If the condition held when the oracle was last called
+    holds: bool,
+}
+
+/// The phases of shutting down the contract, in order
+#[derive(
+    Serialize, Deserialize, BorshDeserialize, BorshSerialize, Debug, PartialEq, Clone, Copy,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum ShutdownPhase {
+    /// Operating normally
+    Running,
+    /// Creation is paused and subscriptions are being settled and removed
+    Settling,
+    /// Balances are being refunded to their accounts
+    Refunding,
+    /// Every balance has been refunded and the contract may be deleted
+    Closed,
+}
+
+/// A request from a destination for the source to stream to it
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StreamRequest {
+    /// The account asked to stream
+    source: AccountId,
+    /// The account requesting the stream
+    destination: AccountId,
+    /// Rate of stream
+    flow: YoctosPerSecond,
+    /// The time the stream ends, if it is bounded
+    end_timestamp: Option<Seconds>,
+}
+
+/// A subscription signed by its source for a relayer to create on its behalf.  The source signs
+/// the borsh serialization of the payload.
+#[derive(Serialize, Deserialize, BorshSerialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SignedCreate {
+    /// The contract the payload is signed for, so it can't be replayed against another
+    contract_id: AccountId,
+    /// Source account which signed the payload
+    source: AccountId,
+    /// Destination account
+    destination: AccountId,
+    /// Rate of stream
+    flow: U128,
+    /// Greater than any nonce the source has used before, so the payload can't be replayed
+    nonce: u64,
+}
+
+/// The message of an `ft_transfer_call` of wNEAR to the contract, topping up the sender's balance
+/// and updating the flow of its subscription
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TopUp {
+    /// Subscription of the sender to update
+    subscription_index: SubscriptionIndex,
+    /// The new flow of the subscription
+    new_flow: U128,
+}
+
+/// A template a source can create subscriptions from
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Template {
+    /// Rate of stream
+    flow: YoctosPerSecond,
+    /// The duration of the stream from creation, if it is bounded
+    end_offset: Option<Seconds>,
+}
+
+/// Statistics across the contract
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ContractStats {
+    /// Subscriptions which have not been removed
+    active_subscriptions: u64,
+    /// Total flow of the active subscriptions
+    committed_flow: U128,
+    /// Total sNEAR credited to the contract
+    streaming_balance: U128,
+    /// Accounts which are frozen
+    frozen_accounts: u64,
+    /// The time the statistics were taken
+    timestamp: Seconds,
+}
+
+/// Subscriptions of an account in both directions with the counterparty and flow of each
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SubscriptionGraph {
+    /// Subscriptions where the account is destination, with the source
+    incoming: Vec<(SubscriptionIndex, AccountId, U128)>,
+    /// Subscriptions where the account is source, with the destination
+    outgoing: Vec<(SubscriptionIndex, AccountId, U128)>,
 }
 
 /// Subscriptions for the Paystream contract
@@ -75,8 +554,10 @@ pub struct Subscriptions {
     pub inputs: LookupMap<AccountId, Vec<SubscriptionIndex>>,
 }
 
-#[derive(Serialize, Deserialize)]
-#[serde(crate = "near_sdk::serde")]
+/// Errors for subscriptions, serialized as `{"error": <variant>, "data": <fields>}` so clients can
+/// match on the variant
+#[derive(Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde", tag = "error", content = "data")]
 pub enum SubscriptionError {
     NotPresent(SubscriptionIndex),
     InvalidFlow(YoctosPerSecond),
@@ -95,6 +576,13 @@ impl std::fmt::Debug for SubscriptionError {
     }
 }
 
+impl SubscriptionError {
+    /// The error as JSON for clients
+    pub fn to_json_string(&self) -> String {
+        near_sdk::serde_json::to_string(self).expect("error serializes")
+    }
+}
+
 type SubscriptionResult = Result<Subscription, SubscriptionError>;
 
 impl Subscriptions {
@@ -104,14 +592,35 @@ impl Subscriptions {
         source: AccountId,
         destination: AccountId,
         flow: YoctosPerSecond,
+        end_timestamp: Option<Seconds>,
+        paid_from: Seconds,
     ) -> Subscription {
-        self.subscription_index = self.subscription_index.wrapping_add(1);
+        self.subscription_index = self
+            .subscription_index
+            .checked_add(1)
+            .expect("index space exhausted");
 
         let subscription = Subscription {
             source: source.clone(),
             destination: destination.clone(),
             flow,
             timestamp: env::block_timestamp(),
+            end_timestamp,
+            paid_from,
+            created_at: env::block_timestamp(),
+            lump_sum: 0,
+            unlock_at: 0,
+            locked_reserve: 0,
+            pull: true,
+            total_settled: 0,
+            active: true,
+            cap: None,
+            condition: None,
+            scaled_flow: None,
+            scaled_remainder: 0,
+            notify: None,
+            group_id: None,
+            frozen_offset: 0,
         };
         self.subscriptions
             .insert(&self.subscription_index, &subscription);
@@ -151,13 +660,13 @@ impl Subscriptions {
             .remove(&subscription_index)
             .ok_or(SubscriptionError::NotPresent(subscription_index))?;
 
-        if let Some(mut inputs) = self.inputs.get(&subscription.source) {
-            inputs.retain(|&input| input == subscription_index);
-            self.inputs.insert(&subscription.source, &inputs);
+        if let Some(mut inputs) = self.inputs.get(&subscription.destination) {
+            inputs.retain(|&input| input != subscription_index);
+            self.inputs.insert(&subscription.destination, &inputs);
         }
 
-        if let Some(mut outputs) = self.outputs.get(&subscription.destination) {
-            outputs.retain(|&output| output == subscription_index);
+        if let Some(mut outputs) = self.outputs.get(&subscription.source) {
+            outputs.retain(|&output| output != subscription_index);
             self.outputs.insert(&subscription.source, &outputs);
         }
 
@@ -172,6 +681,43 @@ impl Subscriptions {
         inputs
     }
 
+    /// Try to replace a stored subscription
+    fn try_replace(
+        &mut self,
+        subscription_index: SubscriptionIndex,
+        subscription: Subscription,
+    ) -> SubscriptionResult {
+        self.try_get(subscription_index)?;
+        self.subscriptions
+            .insert(&subscription_index, &subscription);
+        Ok(subscription)
+    }
+
+    /// Try to move a subscription to a new destination, updating the inputs of both accounts
+    fn try_update_destination(
+        &mut self,
+        subscription_index: SubscriptionIndex,
+        subscription: Subscription,
+    ) -> SubscriptionResult {
+        let previous = self.try_get(subscription_index)?;
+        self.subscriptions
+            .insert(&subscription_index, &subscription);
+
+        if let Some(mut inputs) = self.inputs.get(&previous.destination) {
+            inputs.retain(|&input| input != subscription_index);
+            self.inputs.insert(&previous.destination, &inputs);
+        }
+
+        let mut inputs = self
+            .inputs
+            .get(&subscription.destination)
+            .unwrap_or_default();
+        inputs.push(subscription_index);
+        self.inputs.insert(&subscription.destination, &inputs);
+
+        Ok(subscription)
+    }
+
     /// Try to update the subscription with a new flow
     fn try_update(
         &mut self,
@@ -183,8 +729,10 @@ impl Subscriptions {
             return Err(SubscriptionError::InvalidFlow(new_flow));
         }
         subscription.flow = new_flow;
+        subscription.scaled_flow = None;
+        subscription.scaled_remainder = 0;
         self.subscriptions
-            .insert(&self.subscription_index, &subscription)
+            .insert(&subscription_index, &subscription)
             .ok_or(SubscriptionError::InternalError)?;
 
         Ok(subscription)
@@ -208,6 +756,8 @@ pub struct Paystream {
     metadata: LazyOption<FungibleTokenMetadata>,
     /// Balances of streams in sNEAR
     balances: LookupMap<AccountId, Balance>,
+    /// Settled sNEAR waiting to be claimed by destinations
+    claimable: LookupMap<AccountId, Balance>,
     /// The owner of the contract
     owner: AccountId,
     /// The treasury controlling account
@@ -216,35 +766,229 @@ pub struct Paystream {
     subscriptions: Subscriptions,
     /// Reserve required for subscription in seconds
     reserve: Seconds,
+    /// Minimum flow allowed for a subscription
+    min_flow: YoctosPerSecond,
+    /// When each source last created a subscription
+    last_create: LookupMap<AccountId, Seconds>,
+    /// Cooldown between subscriptions created by a source in seconds
+    create_cooldown: Seconds,
+    /// Accounts frozen from creating or receiving subscriptions
+    frozen: LookupSet<AccountId>,
+    /// Templates saved by sources
+    templates: LookupMap<(AccountId, String), Template>,
+    /// Fee taken by the treasury on settlement
+    fee_basis_points: BasisPoints,
+    /// Maximum subscriptions an account may be the source or destination of
+    max_subscriptions_per_account: u32,
+    /// Subscriptions which have not been removed
+    active_subscriptions: u64,
+    /// Total flow of the active subscriptions
+    committed_flow: YoctosPerSecond,
+    /// Total sNEAR credited to the contract
+    streaming_balance: Balance,
+    /// Accounts which are frozen
+    frozen_accounts: u64,
+    /// End times destinations have approved shortening their subscriptions to
+    end_time_approvals: LookupMap<SubscriptionIndex, Seconds>,
+    /// Aggregate flows of each account
+    account_flows: LookupMap<AccountId, AccountFlow>,
+    /// The time accrual was frozen, nothing accrues after this while frozen
+    frozen_at: Option<Seconds>,
+    /// Total time accrual has been frozen for.  Subscriptions and account flows are moved
+    /// forward by what of it has passed since they were stored when they are next read.
+    frozen_offset: Seconds,
+    /// Decimals of the wrap contract once verified as a fungible token
+    wrap_decimals: Option<u8>,
+    /// Metadata of each token registered with the contract by token id
+    token_metadata: LookupMap<AccountId, FungibleTokenMetadata>,
+    /// Fees collected on settlement and not yet withdrawn by the treasurer
+    fees_collected: Balance,
+    /// Destinations each source has allowed, as source and destination
+    allowed_destinations: LookupSet<(AccountId, AccountId)>,
+    /// Sources which only stream to their allowed destinations
+    enforce_allowlist: LookupSet<AccountId>,
+    /// Maximum total flow each destination has agreed to receive
+    max_incoming_flow: LookupMap<AccountId, YoctosPerSecond>,
+    /// The last nonce settlement was processed with for each subscription
+    settlement_nonces: LookupMap<SubscriptionIndex, u64>,
+    /// The flows of each subscription with the time each took effect
+    flow_history: LookupMap<SubscriptionIndex, Vec<(Seconds, YoctosPerSecond)>>,
+    /// Index of the last stream request
+    request_index: RequestId,
+    /// Pending stream requests
+    requests: LookupMap<RequestId, StreamRequest>,
+    /// Pending stream requests of each source
+    source_requests: LookupMap<AccountId, Vec<RequestId>>,
+    /// The time the contract was initialised
+    deployed_at: Seconds,
+    /// Reserve windows required of flows at or above each threshold, ascending by threshold.
+    /// Flows below every threshold require `reserve`.
+    reserve_tiers: Vec<(YoctosPerSecond, Seconds)>,
+    /// Destinations, typically contracts, which must acknowledge a stream before it accrues
+    acknowledgment_required: LookupSet<AccountId>,
+    /// The key each account signs payloads relayed on its behalf with
+    relay_keys: LookupMap<AccountId, PublicKey>,
+    /// The last nonce of a relayed payload used by each account
+    relay_nonces: LookupMap<AccountId, u64>,
+    /// How fractions of a yocto are rounded in fees
+    rounding_mode: RoundingMode,
+    /// Fees collected on each day since the epoch, by day index
+    fees_by_day: LookupMap<u64, Balance>,
+    /// How far the contract is through shutting down
+    shutdown_phase: ShutdownPhase,
+    /// The next subscription to settle while shutting down
+    shutdown_cursor: SubscriptionIndex,
+    /// The next holder to check has been refunded while refunding
+    refund_cursor: u64,
+    /// Accounts which have been credited a balance or claimable sNEAR, in the order first
+    /// credited
+    holders: Vector<AccountId>,
+    /// Accounts which have been credited a balance or claimable sNEAR
+    holder_set: LookupSet<AccountId>,
+    /// Account notified of settlement of each destination's subscriptions
+    notification_targets: LookupMap<AccountId, AccountId>,
+    /// Funds locked by the source of each escrowed subscription and not yet streamed
+    escrows: LookupMap<SubscriptionIndex, Balance>,
+    /// Onward destination and share forwarded of what settles on each incoming subscription
+    passthroughs: LookupMap<SubscriptionIndex, (AccountId, BasisPoints)>,
+    /// Id of the last group created
+    group_index: GroupId,
+    /// Subscriptions in each group
+    groups: LookupMap<GroupId, Vec<SubscriptionIndex>>,
+    /// Account which created each group
+    group_owners: LookupMap<GroupId, AccountId>,
+    /// Payees and their weights that each destination divides what settles to it between
+    incoming_splits: LookupMap<AccountId, Vec<(AccountId, u32)>>,
+    /// How long after creation the source may claw a subscription back
+    clawback_seconds: Seconds,
+    /// Net amounts the settlements of each subscription credited to each account, to the
+    /// claimable balance when pulled, so that a clawback reverses exactly those
+    settlement_credits: LookupMap<SubscriptionIndex, Vec<(AccountId, bool, Balance)>>,
+    /// Total of the reserves locked by the outgoing subscriptions of each source
+    locked_reserves: LookupMap<AccountId, Balance>,
+}
+
+/// Subscription as stored by version 0.1.0 of the contract
+#[derive(BorshDeserialize, BorshSerialize)]
+struct SubscriptionV1 {
+    source: AccountId,
+    destination: AccountId,
+    flow: YoctosPerSecond,
+    timestamp: Seconds,
+}
+
+/// Paystream as stored by version 0.1.0 of the contract
+#[derive(BorshDeserialize, BorshSerialize)]
+struct PaystreamV1 {
+    wrap_contract: AccountId,
+    token: FungibleToken,
+    metadata: LazyOption<FungibleTokenMetadata>,
+    balances: LookupMap<AccountId, Balance>,
+    owner: AccountId,
+    treasurer: AccountId,
+    subscriptions: Subscriptions,
+    reserve: Seconds,
 }
 
 // sNEAR fungible token
 // We wrap wNEAR so you could say a wrap of a wrapper
-// Over the testnet we call `wrap`
-const WRAP_CONTRACT: &str = "wrap.testnet";
 const STREAM_SYMBOL: &str = "STREAM";
 const STREAM_NAME: &str = "sNEAR fungible token";
 const DECIMALS: u8 = 24;
+const MAX_BASIS_POINTS: BasisPoints = 10_000;
+const SECONDS_PER_DAY: Seconds = 24 * 60 * 60;
+const SECONDS_PER_YEAR: Seconds = 365 * SECONDS_PER_DAY;
+const SECONDS_PER_MONTH: Seconds = 30 * SECONDS_PER_DAY;
+const FLOW_SCALE: u128 = 1_000_000;
+// Most accounts a bulk balance query may list, to stay within the gas of a view call
+const MAX_BALANCE_QUERY: usize = 100;
+// Most payees a destination may split what it receives between
+const MAX_INCOMING_SPLITS: usize = 10;
+// Most stream requests a source may have pending, so requests can't be piled on it
+const MAX_PENDING_REQUESTS: usize = 20;
+// Most of those a single destination may have made, so one destination can't take them all
+const MAX_PENDING_REQUESTS_PER_DESTINATION: usize = 2;
+const DEFAULT_CLAWBACK_SECONDS: Seconds = 60 * 60;
+// Most holders checked for a remaining balance by each call refunding accounts
+const REFUND_SCAN_LIMIT: u64 = 100;
+// Capabilities clients can negotiate on, sNEAR is the only token streamed
+const SUPPORTED_FEATURES: [&str; 18] = [
+    "pull_settlement",
+    "scheduled_payments",
+    "trial_subscriptions",
+    "templates",
+    "stream_requests",
+    "capped_subscriptions",
+    "conditional_subscriptions",
+    "scaled_flow",
+    "destination_acknowledgment",
+    "destination_allowlist",
+    "relayed_create",
+    "settlement_notifications",
+    "escrowed_subscriptions",
+    "passthrough",
+    "subscription_groups",
+    "future_start",
+    "incoming_splits",
+    "clawback",
+];
+// Bytes charged by the runtime for each record in storage beyond its key and value
+const STORAGE_RECORD_BYTES: u64 = 40;
 // TODO change this symbol
 const DATA_IMAGE_SVG_NEAR_ICON: &str = "data:image/svg+xml,%3Csvg xmlns='http://www.w3.org/2000/svg' viewBox='0 0 288 288'%3E%3Cg id='l' data-name='l'%3E%3Cpath d='M187.58,79.81l-30.1,44.69a3.2,3.2,0,0,0,4.75,4.2L191.86,103a1.2,1.2,0,0,1,2,.91v80.46a1.2,1.2,0,0,1-2.12.77L102.18,77.93A15.35,15.35,0,0,0,90.47,72.5H87.34A15.34,15.34,0,0,0,72,87.84V201.16A15.34,15.34,0,0,0,87.34,216.5h0a15.35,15.35,0,0,0,13.08-7.31l30.1-44.69a3.2,3.2,0,0,0-4.75-4.2L96.14,186a1.2,1.2,0,0,1-2-.91V104.61a1.2,1.2,0,0,1,2.12-.77l89.55,107.23a15.35,15.35,0,0,0,11.71,5.43h3.13A15.34,15.34,0,0,0,216,201.16V87.84A15.34,15.34,0,0,0,200.66,72.5h0A15.35,15.35,0,0,0,187.58,79.81Z'/%3E%3C/g%3E%3C/svg%3E";
 
 #[ext_contract(ext_ft)]
 pub trait FungibleToken {
+    #[payable]
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
     fn ft_balance_of(&mut self, account_id: AccountId) -> U128;
+    fn ft_metadata(&self) -> FungibleTokenMetadata;
+}
+
+#[ext_contract(ext_oracle)]
+pub trait PriceOracle {
+    fn get_price(&self) -> U128;
+}
+
+#[ext_contract(ext_notify)]
+pub trait SettlementReceiver {
+    fn on_settlement(&mut self, subscription_index: SubscriptionIndex, amount: U128);
 }
 
 #[ext_contract(ext_wnear)]
 pub trait wNear {
     #[payable]
     fn near_deposit(&mut self);
+    #[payable]
+    fn near_withdraw(&mut self, amount: U128);
 }
 
 #[ext_contract(ext_self)]
 pub trait Callbacks {
     fn wrap_callback(&mut self, account_id: AccountId, amount: Balance);
+    fn verify_wrap_callback(&mut self, account_id: AccountId, amount: Balance) -> Promise;
+    fn unwrap_callback(&mut self, account_id: AccountId, amount: Balance);
+    fn poke_callback(&mut self, subscription_index: SubscriptionIndex);
+    fn solvency_callback(&self) -> bool;
 }
 
-/// Permissions based on account
+/// Log an event in the NEP-297 format
+fn emit_event(event: &str, data: Value) {
+    log!(
+        "EVENT_JSON:{}",
+        json!({
+            "standard": "paystream",
+            "version": "1.0.0",
+            "event": event,
+            "data": [data],
+        })
+    );
+}
+
+/// Permissions based on account.  Every check is made against the predecessor, the account which
+/// called the contract, rather than the signer of the transaction.  A contract or relayer calling
+/// on behalf of the signer would otherwise act with the signer's permissions, so any account the
+/// signer interacts with could create or remove its subscriptions.
 trait Permission {
     fn required(account_id: &AccountId);
 }
@@ -252,7 +996,7 @@ trait Permission {
 impl Permission for Paystream {
     fn required(account_id: &AccountId) {
         require!(
-            &env::signer_account_id() == account_id,
+            &env::predecessor_account_id() == account_id,
             "Permission required"
         );
     }
@@ -272,459 +1016,7688 @@ impl Paystream {
         require!(&new_owner != self.owner(), "should be new owner");
         self.owner = new_owner;
     }
-}
 
-// Treasury control
-#[near_bindgen]
-impl Paystream {
-    /// Return who the treasurer of the contract is
-    pub fn treasurer(&self) -> &AccountId {
-        &self.treasurer
+    /// Update the metadata of sNEAR, owner gated.  The decimals can't be changed as balances are
+    /// held in them.
+    pub fn set_metadata(
+        &mut self,
+        name: String,
+        symbol: String,
+        icon: Option<String>,
+        reference: Option<String>,
+        reference_hash: Option<Base64VecU8>,
+    ) {
+        Self::required(self.owner());
+        let metadata = FungibleTokenMetadata {
+            spec: FT_METADATA_SPEC.into(),
+            name,
+            symbol,
+            icon,
+            reference,
+            reference_hash,
+            decimals: DECIMALS,
+        };
+        metadata.assert_valid();
+        self.metadata.set(&metadata);
+        self.token_metadata
+            .insert(&env::current_account_id(), &metadata);
     }
 
-    /// Treasurer can be set only by `owner`
-    pub fn set_treasurer(&mut self, new_treasurer: AccountId) {
+    /// Settle subscriptions in index order starting at `from_index`, visiting at most `limit`
+    /// indices, owner gated.  Removed subscriptions are skipped and a source that can't cover
+    /// its stream settles what it can afford.  Returns the number of indices visited, call again
+    /// from `from_index` plus this until it returns zero.
+    pub fn admin_settle_batch(&mut self, from_index: SubscriptionIndex, limit: u64) -> u64 {
+        Self::required(self.owner());
+        let to_index = from_index
+            .saturating_add(limit)
+            .min(self.subscriptions.subscription_index.saturating_add(1));
+        // Index zero is never a subscription but counts towards the indices visited
+        for subscription_index in from_index.max(1)..to_index {
+            if let Some(subscription) = self.subscription(subscription_index) {
+                self.internal_settle_partial(subscription_index, subscription);
+            }
+        }
+        to_index.saturating_sub(from_index)
+    }
+
+    /// Accounts with a non-zero current balance and the balance.  Up to `limit` accounts are
+    /// visited in the order they were first credited, starting at `from_index`.  A view, the
+    /// balances are public through `ft_balance_of` already.
+    pub fn balance_snapshot(&self, from_index: u64, limit: u64) -> Vec<(AccountId, U128)> {
+        let to_index = from_index.saturating_add(limit).min(self.holders.len());
+        (from_index..to_index)
+            .filter_map(|index| self.holders.get(index))
+            .map(|account_id| {
+                let balance = self.current_balance(account_id.clone());
+                (account_id, balance)
+            })
+            .filter(|(_, balance)| balance.0 > 0)
+            .collect()
+    }
+
+    /// Correct the time a subscription started, owner gated.  The subscription is settled up to
+    /// now first and then its accrual is moved by the correction, so the destination is paid for
+    /// time it was missed or stops accruing until it has repaid time it was overpaid.
+    pub fn admin_set_timestamp(
+        &mut self,
+        subscription_index: SubscriptionIndex,
+        timestamp: Seconds,
+    ) {
         Self::required(self.owner());
         require!(
-            &new_treasurer != self.treasurer(),
-            "should be new treasurer"
+            timestamp <= env::block_timestamp(),
+            "timestamp must not be in the future"
+        );
+        let subscription = self.try_subscription(subscription_index).unwrap();
+        self.internal_settle_partial(subscription_index, subscription);
+
+        let mut subscription = self.try_subscription(subscription_index).unwrap();
+        self.untrack(subscription_index, &subscription);
+        let previous = subscription.created_at;
+        let shift = |at: Seconds| {
+            if timestamp < previous {
+                at.saturating_sub(previous - timestamp)
+            } else {
+                at.saturating_add(timestamp - previous)
+            }
+        };
+        subscription.timestamp = shift(subscription.timestamp);
+        subscription.paid_from = shift(subscription.paid_from);
+        if subscription.lump_sum > 0 {
+            subscription.unlock_at = shift(subscription.unlock_at);
+        }
+        subscription.created_at = timestamp;
+        let subscription = self
+            .subscriptions
+            .try_replace(subscription_index, subscription)
+            .unwrap();
+        self.track(subscription_index, &subscription);
+        emit_event(
+            "timestamp_corrected",
+            json!({
+                "subscription_index": subscription_index,
+                "previous": previous,
+                "timestamp": timestamp,
+                "owner": self.owner(),
+            }),
         );
-        self.treasurer = new_treasurer;
     }
 }
 
+// Compliance control
 #[near_bindgen]
 impl Paystream {
-    /// Calculate the reserve we would need to be able to create a subscription
-    fn sufficient_reserve(&self, rate: YoctosPerSecond, account_id: &AccountId) {
-        let minimum_balance = rate.saturating_mul(self.reserve as u128);
-        let current_balance = self
-            .balances
-            .get(account_id)
-            .expect("that source has balance");
-        require!(
-            current_balance > minimum_balance,
-            "sufficient reserve is required"
-        );
+    /// If the account is frozen
+    pub fn is_frozen(&self, account_id: AccountId) -> bool {
+        self.frozen.contains(&account_id)
     }
 
-    /// Update the reserve stored in the contract, owner gated
-    pub fn update_reserve(&mut self, reserve: Seconds) {
+    /// Freeze an account from creating or receiving subscriptions, owner gated.  Existing
+    /// subscriptions can still be removed and settled.
+    pub fn freeze_account(&mut self, account_id: AccountId) {
         Self::required(self.owner());
-        self.reserve = reserve;
+        require!(self.frozen.insert(&account_id), "account already frozen");
+        self.frozen_accounts += 1;
     }
 
-    /// Create a subscription.  If the subscription meets the reserve requirements for the signer
-    /// we create it and payment started from this moment.
-    pub fn create_subscription(
-        &mut self,
-        source: AccountId,
-        destination: AccountId,
-        rate: YoctosPerSecond,
-    ) -> Subscription {
-        require!(rate > 0, "rate needs to be greater than zero");
-        require!(source == env::signer_account_id(), "signer must be source");
-        require!(source != destination, "source must not be destination");
-        // Validate that we have enough in the account to create the subscription(reserve)
-        self.sufficient_reserve(rate, &source);
-        self.subscriptions.create(source, destination, rate)
+    /// Unfreeze an account, owner gated
+    pub fn unfreeze_account(&mut self, account_id: AccountId) {
+        Self::required(self.owner());
+        require!(self.frozen.remove(&account_id), "account not frozen");
+        self.frozen_accounts -= 1;
     }
 
-    /// Remove subscription.  The signer maybe the source or destination of the subscription.
-    /// On removal the stream is settled at this moment in time, the stream from then would have
-    /// stopped. 
-    pub fn remove_subscription(&mut self, subscription_index: SubscriptionIndex) -> Subscription {
-        let subscription = self.subscriptions.try_get(subscription_index).unwrap();
+    /// Move a compromised account to a new account, owner gated.  The old account's balances and
+    /// subscriptions are moved with each subscription streaming from or to the new account,
+    /// together with what is kept for them: settlement credits, passthroughs and the groups they
+    /// are in.  The old account's settings as a destination and its allowlist enforcement move
+    /// too.  Templates and allowed destinations can't be enumerated so stay with the old account,
+    /// the new account has to allow its destinations again.  The new account must not already
+    /// have subscriptions.
+    pub fn recover_account(&mut self, old: AccountId, new: AccountId) {
+        Self::required(self.owner());
+        require!(old != new, "should be new account");
+        require!(!self.frozen.contains(&new), "account is frozen");
         require!(
-            subscription.source == env::signer_account_id()
-                || subscription.destination == env::signer_account_id(),
-            "signer must be source or destination"
+            self.subscriptions
+                .subscriptions_for_account(new.clone())
+                .is_empty(),
+            "new account has subscriptions"
         );
 
-        let mut subscription = self
-            .subscriptions
-            .try_remove(subscription_index)
-            .expect("subscription is removed");
-
-        let amount = subscription.settle();
-        self.try_transfer(
-            subscription.source.clone(),
-            subscription.destination.clone(),
-            amount,
-        )
-        .expect("transfer on settlement");
+        let inputs = self.subscriptions.inputs.remove(&old).unwrap_or_default();
+        let outputs = self.subscriptions.outputs.remove(&old).unwrap_or_default();
+        for subscription_index in inputs.iter().chain(outputs.iter()) {
+            if let Some(mut subscription) = self.subscription(*subscription_index) {
+                self.untrack(*subscription_index, &subscription);
+                if subscription.source == old {
+                    subscription.source = new.clone();
+                } else {
+                    subscription.destination = new.clone();
+                }
+                if let Some(mut credits) = self.settlement_credits.get(subscription_index) {
+                    for (account_id, _, _) in credits.iter_mut() {
+                        if *account_id == old {
+                            *account_id = new.clone();
+                        }
+                    }
+                    self.settlement_credits.insert(subscription_index, &credits);
+                }
+                if let Some((onward_destination, fraction_bps)) =
+                    self.passthroughs.get(subscription_index)
+                {
+                    if onward_destination == old {
+                        self.passthroughs
+                            .insert(subscription_index, &(new.clone(), fraction_bps));
+                    }
+                }
+                if let Some(group_id) = subscription.group_id {
+                    if self.group_owners.get(&group_id) == Some(old.clone()) {
+                        self.group_owners.insert(&group_id, &new);
+                    }
+                }
+                self.track(*subscription_index, &subscription);
+                self.subscriptions
+                    .try_replace(*subscription_index, subscription)
+                    .unwrap();
+            }
+        }
+        if !inputs.is_empty() {
+            self.subscriptions.inputs.insert(&new, &inputs);
+        }
+        if !outputs.is_empty() {
+            self.subscriptions.outputs.insert(&new, &outputs);
+        }
+        self.account_flows.remove(&old);
+        if let Some(max) = self.max_incoming_flow.remove(&old) {
+            self.max_incoming_flow.insert(&new, &max);
+        }
+        if self.acknowledgment_required.remove(&old) {
+            self.acknowledgment_required.insert(&new);
+        }
+        if self.enforce_allowlist.remove(&old) {
+            self.enforce_allowlist.insert(&new);
+        }
+        if let Some(target) = self.notification_targets.remove(&old) {
+            self.notification_targets.insert(&new, &target);
+        }
+        if let Some(mut splits) = self.incoming_splits.remove(&old) {
+            for (payee, _) in splits.iter_mut() {
+                if *payee == old {
+                    *payee = new.clone();
+                }
+            }
+            self.incoming_splits.insert(&new, &splits);
+        }
+        if let Some(locked_reserve) = self.locked_reserves.remove(&old) {
+            self.lock_reserve(&new, locked_reserve);
+        }
 
-        subscription
+        if let Some(balance) = self.balances.remove(&old) {
+            let current_balance = self.balances.get(&new).unwrap_or_default();
+            self.balances
+                .insert(&new, &current_balance.saturating_add(balance));
+            self.record_holder(&new);
+        }
+        if let Some(claimable) = self.claimable.remove(&old) {
+            let current_claimable = self.claimable.get(&new).unwrap_or_default();
+            self.claimable
+                .insert(&new, &current_claimable.saturating_add(claimable));
+            self.record_holder(&new);
+        }
     }
 
-    /// Subscriptions for the signing account
-    pub fn subscriptions_by_account(&self) -> Vec<SubscriptionIndex> {
-        self.subscriptions.subscriptions_for_account(env::signer_account_id())
+    /// Freeze accrual across all subscriptions, owner gated.  While frozen streams are settled
+    /// only up to the time of freezing.
+    pub fn freeze_accrual(&mut self) {
+        Self::required(self.owner());
+        require!(self.frozen_at.is_none(), "accrual already frozen");
+        self.frozen_at = Some(env::block_timestamp());
     }
 
-    /// A subscription by index
-    pub fn get_subscription(&self, subscription_index: SubscriptionIndex) -> Subscription {
-        self.subscriptions.try_get(subscription_index).unwrap()
+    /// Unfreeze accrual, owner gated.  Subscriptions are shifted forward by the time accrual was
+    /// frozen so that nothing accrues for the frozen window, each as it is next read.
+    pub fn unfreeze_accrual(&mut self) {
+        Self::required(self.owner());
+        let frozen_at = self.frozen_at.take().expect("accrual not frozen");
+        let frozen_duration = env::block_timestamp().saturating_sub(frozen_at);
+        self.frozen_offset = self.frozen_offset.saturating_add(frozen_duration);
     }
+}
 
-    /// Update the flow of the subscription.  Changing the flow will force the stream to be settled
-    /// at this point in time and from then the new flow will take effect.
-    pub fn update_subscription(
-        &mut self,
-        subscription_index: SubscriptionIndex,
-        new_flow: YoctosPerSecond,
-    ) -> Subscription {
-        let mut subscription = self.subscriptions.try_get(subscription_index).unwrap();
-        let amount = subscription.settle();
-        self.try_transfer(subscription.source, subscription.destination, amount)
-            .unwrap();
-        self.subscriptions
-            .try_update(subscription_index, new_flow)
+// Shutdown
+#[near_bindgen]
+impl Paystream {
+    /// How far the contract is through shutting down
+    pub fn shutdown_phase(&self) -> ShutdownPhase {
+        self.shutdown_phase
+    }
+
+    /// Start shutting down the contract, owner gated.  Creation is paused and accrual is frozen
+    /// so streams stop at this moment, then `advance_shutdown` settles the subscriptions.
+    pub fn initiate_shutdown(&mut self) {
+        Self::required(self.owner());
+        require!(
+            self.shutdown_phase == ShutdownPhase::Running,
+            "shutdown already initiated"
+        );
+        self.shutdown_phase = ShutdownPhase::Settling;
+        self.frozen_at.get_or_insert(env::block_timestamp());
+    }
+
+    /// Settle and remove up to `limit` subscriptions, owner gated.  A source that can't cover
+    /// its stream settles what it can afford.  Once every subscription has been removed the
+    /// uncollected fees are credited to the treasurer and refunding starts.
+    pub fn advance_shutdown(&mut self, limit: u64) -> ShutdownPhase {
+        Self::required(self.owner());
+        require!(
+            self.shutdown_phase == ShutdownPhase::Settling,
+            "shutdown not settling"
+        );
+        let to_index = self
+            .shutdown_cursor
+            .saturating_add(limit)
+            .min(self.subscriptions.subscription_index.saturating_add(1));
+        for subscription_index in self.shutdown_cursor..to_index {
+            if let Some(subscription) = self.subscription(subscription_index) {
+                self.internal_settle_partial(subscription_index, subscription);
+            }
+            // A capped subscription may have completed on settlement
+            if let Ok(subscription) = self
+                .subscriptions
+                .try_remove(subscription_index)
+                .map(|subscription| self.thawed(subscription))
+            {
+                self.forget_subscription(subscription_index, &subscription);
+                self.untrack(subscription_index, &subscription);
+                self.active_subscriptions -= 1;
+                self.committed_flow = self.committed_flow.saturating_sub(subscription.flow);
+            }
+        }
+        self.shutdown_cursor = to_index;
+
+        if self.shutdown_cursor > self.subscriptions.subscription_index {
+            let fees = std::mem::take(&mut self.fees_collected);
+            let treasurer = self.treasurer.clone();
+            let balance = self.balances.get(&treasurer).unwrap_or_default();
+            self.balances
+                .insert(&treasurer, &balance.saturating_add(fees));
+            self.record_holder(&treasurer);
+            self.shutdown_phase = ShutdownPhase::Refunding;
+        }
+        self.shutdown_phase
+    }
+
+    /// Refund the balance and claimable sNEAR of the accounts to them in NEAR, owner gated.  The
+    /// contract is closed once every holder has been refunded, checking up to
+    /// `REFUND_SCAN_LIMIT` holders a call in the order first credited.
+    pub fn refund_accounts(&mut self, accounts: Vec<AccountId>) -> ShutdownPhase {
+        Self::required(self.owner());
+        require!(
+            self.shutdown_phase == ShutdownPhase::Refunding,
+            "shutdown not refunding"
+        );
+        for account_id in accounts {
+            let amount = self
+                .balances
+                .remove(&account_id)
+                .unwrap_or_default()
+                .saturating_add(self.claimable.remove(&account_id).unwrap_or_default());
+            if amount > 0 {
+                self.streaming_balance = self.streaming_balance.saturating_sub(amount);
+                self.withdraw_to_account(account_id, amount);
+            }
+        }
+
+        let to_index = self
+            .refund_cursor
+            .saturating_add(REFUND_SCAN_LIMIT)
+            .min(self.holders.len());
+        while self.refund_cursor < to_index {
+            let holder = self.holders.get(self.refund_cursor).expect("holder exists");
+            if self.balances.get(&holder).unwrap_or_default() > 0
+                || self.claimable.get(&holder).unwrap_or_default() > 0
+            {
+                break;
+            }
+            self.refund_cursor += 1;
+        }
+        if self.refund_cursor == self.holders.len() {
+            self.shutdown_phase = ShutdownPhase::Closed;
+        }
+        self.shutdown_phase
+    }
+
+    /// Delete the contract and its state once closed, sending what remains of its NEAR to the
+    /// beneficiary, owner gated
+    pub fn delete_contract(&mut self, beneficiary: AccountId) -> Promise {
+        Self::required(self.owner());
+        require!(
+            self.shutdown_phase == ShutdownPhase::Closed,
+            "shutdown not closed"
+        );
+        Promise::new(env::current_account_id()).delete_account(beneficiary)
+    }
+}
+
+// Conditional streams
+#[near_bindgen]
+impl Paystream {
+    /// Ask the oracle of a conditional subscription for the price, starting or stopping accrual
+    /// as the condition now holds or not.  Anyone may poke a subscription.
+    pub fn poke(&mut self, subscription_index: SubscriptionIndex) -> Promise {
+        let condition = self
+            .try_subscription(subscription_index)
             .unwrap()
+            .condition
+            .expect("subscription is not conditional");
+        ext_oracle::get_price(condition.oracle, 0, 5_000_000_000_000u64.into()).then(
+            ext_self::poke_callback(
+                subscription_index,
+                env::current_account_id(),
+                0,
+                20_000_000_000_000u64.into(),
+            ),
+        )
+    }
+
+    /// Record whether the condition holds for the price the oracle returned.  The subscription is
+    /// settled up to now under the previous state, and accrues from now when it starts to hold.
+    #[private]
+    pub fn poke_callback(&mut self, subscription_index: SubscriptionIndex) {
+        assert_eq!(env::promise_results_count(), 1, "This is a callback method");
+
+        let price = match env::promise_result(0) {
+            PromiseResult::Successful(result) => {
+                near_sdk::serde_json::from_slice::<U128>(&result).ok()
+            }
+            _ => None,
+        };
+        let (subscription, price) = match (self.subscription(subscription_index), price) {
+            (Some(subscription), Some(price)) => (subscription, price),
+            _ => {
+                log!("failed to poke subscription [{}]", subscription_index);
+                return;
+            }
+        };
+        let holds = subscription
+            .condition
+            .as_ref()
+            .map(|condition| condition.condition.holds(price.0))
+            .expect("subscription is conditional");
+        if subscription
+            .condition
+            .as_ref()
+            .is_some_and(|condition| condition.holds == holds)
+        {
+            return;
+        }
+
+        self.internal_settle_partial(subscription_index, subscription);
+        // A capped subscription may have completed on settlement
+        let mut subscription = match self.subscription(subscription_index) {
+            Some(subscription) => subscription,
+            None => return,
+        };
+        self.untrack(subscription_index, &subscription);
+        if let Some(condition) = subscription.condition.as_mut() {
+            condition.holds = holds;
+        }
+        if holds {
+            subscription.timestamp = subscription.timestamp.max(self.accrual_timestamp());
+        }
+        let subscription = self
+            .subscriptions
+            .try_replace(subscription_index, subscription)
+            .unwrap();
+        self.track(subscription_index, &subscription);
     }
 }
 
+// Destination allowlist
 #[near_bindgen]
 impl Paystream {
-    /// Create the paystream contract with the wrapped token contract wNEAR
-    #[init]
-    pub fn new(owner: AccountId, wrap_contract: AccountId) -> Self {
-        require!(!env::state_exists(), "Already initialized");
-        // Metadata for the wrapped wrapper
-        let metadata = FungibleTokenMetadata {
-            spec: FT_METADATA_SPEC.into(),
-            name: STREAM_NAME.into(),
-            symbol: STREAM_SYMBOL.into(),
-            icon: Some(DATA_IMAGE_SVG_NEAR_ICON.into()),
-            reference: None,
-            reference_hash: None,
-            decimals: DECIMALS,
+    /// Allow the caller to stream to the destination when enforcing its allowlist
+    pub fn add_allowed_destination(&mut self, destination: AccountId) {
+        self.allowed_destinations
+            .insert(&(env::predecessor_account_id(), destination));
+    }
+
+    /// Remove the destination from the allowlist of the caller, existing subscriptions are kept
+    pub fn remove_allowed_destination(&mut self, destination: AccountId) {
+        self.allowed_destinations
+            .remove(&(env::predecessor_account_id(), destination));
+    }
+
+    /// Only allow the caller to create subscriptions to destinations on its allowlist
+    pub fn set_enforce_allowlist(&mut self, enforce_allowlist: bool) {
+        let source = env::predecessor_account_id();
+        if enforce_allowlist {
+            self.enforce_allowlist.insert(&source);
+        } else {
+            self.enforce_allowlist.remove(&source);
+        }
+    }
+
+    /// If the source may stream to the destination under its allowlist
+    pub fn is_allowed_destination(&self, source: AccountId, destination: AccountId) -> bool {
+        !self.enforce_allowlist.contains(&source)
+            || self.allowed_destinations.contains(&(source, destination))
+    }
+}
+
+// Destination inflow cap
+#[near_bindgen]
+impl Paystream {
+    /// Limit the total flow the caller receives across its subscriptions, `None` removes the
+    /// limit.  Existing subscriptions are kept.
+    pub fn set_max_incoming_flow(&mut self, max: Option<YoctosPerSecond>) {
+        let destination = env::predecessor_account_id();
+        match max {
+            Some(max) => self.max_incoming_flow.insert(&destination, &max),
+            None => self.max_incoming_flow.remove(&destination),
         };
-        metadata.assert_valid();
+    }
 
-        // Initialise contract
-        let mut this = Self {
-            wrap_contract,
-            balances: LookupMap::new(StorageKey::Balances),
-            token: FungibleToken::new(StorageKey::FungibleToken),
-            metadata: LazyOption::new(StorageKey::Metadata, Some(&metadata)),
-            owner: owner.clone(),
-            treasurer: owner.clone(),
-            subscriptions: Subscriptions {
-                subscription_index: 0,
-                subscriptions: LookupMap::new(StorageKey::Subscriptions),
-                outputs: LookupMap::new(StorageKey::Outputs),
-                inputs: LookupMap::new(StorageKey::Inputs),
-            },
-            reserve: 4 * 60 * 60, // 4 hours
+    /// The maximum total flow the destination will receive, if limited
+    pub fn max_incoming_flow(&self, destination: AccountId) -> Option<YoctosPerSecond> {
+        self.max_incoming_flow.get(&destination)
+    }
+
+    /// Require streams to the caller to be acknowledged before they accrue.  Contracts which
+    /// must opt in to receiving streams set this.
+    pub fn set_acknowledgment_required(&mut self, required: bool) {
+        let destination = env::predecessor_account_id();
+        if required {
+            self.acknowledgment_required.insert(&destination);
+        } else {
+            self.acknowledgment_required.remove(&destination);
+        }
+    }
+
+    /// Notify the target of settlement of every subscription to the caller which doesn't have
+    /// its own `notify`, `None` stops notifying
+    pub fn set_notification_target(&mut self, target: Option<AccountId>) {
+        let destination = env::predecessor_account_id();
+        match target {
+            Some(target) => self.notification_targets.insert(&destination, &target),
+            None => self.notification_targets.remove(&destination),
         };
+    }
 
-        this.token.internal_register_account(&owner);
-        // No initial supply
-        this.token.internal_deposit(&owner, 0);
-        this
+    /// The account notified of settlement of the destination's subscriptions, if any
+    pub fn notification_target(&self, destination: AccountId) -> Option<AccountId> {
+        self.notification_targets.get(&destination)
     }
 
-    /// Wrap NEAR as wNEAR as a cross contract call and on success credit the
-    /// account's balance as sNEAR
-    #[payable]
-    pub fn wrap_near(&mut self) -> Promise {
-        ext_wnear::near_deposit(
-            WRAP_CONTRACT.parse().unwrap(),
-            env::attached_deposit(),
-            5_000_000_000_000u64.into(),
+    /// Divide what settles to the caller as destination between the payees by weight, the
+    /// caller keeping a share only if listed.  An empty split keeps it all.
+    pub fn set_incoming_split(&mut self, splits: Vec<(AccountId, u32)>) {
+        require!(splits.len() <= MAX_INCOMING_SPLITS, "too many splits");
+        require!(
+            splits.iter().all(|(_, weight)| *weight > 0),
+            "split weight must be greater than zero"
+        );
+        let destination = env::predecessor_account_id();
+        if splits.is_empty() {
+            self.incoming_splits.remove(&destination);
+        } else {
+            self.incoming_splits.insert(&destination, &splits);
+        }
+    }
+
+    /// The payees and weights the destination divides what settles to it between
+    pub fn incoming_split(&self, destination: AccountId) -> Vec<(AccountId, u32)> {
+        self.incoming_splits.get(&destination).unwrap_or_default()
+    }
+
+    /// If streams to the destination must be acknowledged before they accrue
+    pub fn is_acknowledgment_required(&self, destination: AccountId) -> bool {
+        self.acknowledgment_required.contains(&destination)
+    }
+}
+
+impl Paystream {
+    /// Require the destination's total incoming flow to stay within its cap when one of its
+    /// subscriptions changes from the current flow to the new flow
+    fn require_within_incoming_cap(
+        &self,
+        destination: &AccountId,
+        current_flow: YoctosPerSecond,
+        new_flow: YoctosPerSecond,
+    ) {
+        if let Some(max) = self.max_incoming_flow.get(destination) {
+            let incoming_flow = self
+                .subscriptions
+                .inputs
+                .get(destination)
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|subscription_index| self.subscription(*subscription_index))
+                .fold(0, |flow: YoctosPerSecond, subscription| {
+                    flow.saturating_add(subscription.flow)
+                });
+            require!(
+                incoming_flow
+                    .saturating_sub(current_flow)
+                    .saturating_add(new_flow)
+                    <= max,
+                "destination inflow cap exceeded"
+            );
+        }
+    }
+}
+
+// Stream requests
+#[near_bindgen]
+impl Paystream {
+    /// Request the source to stream to the caller, returning the id of the request
+    pub fn request_stream(
+        &mut self,
+        source: AccountId,
+        flow: YoctosPerSecond,
+        end: Option<Seconds>,
+    ) -> RequestId {
+        let destination = env::predecessor_account_id();
+        require!(source != destination, "source must not be destination");
+        require!(
+            end.is_none_or(|end| end > env::block_timestamp()),
+            "end must be in the future"
+        );
+        let mut source_requests = self.source_requests.get(&source).unwrap_or_default();
+        require!(
+            source_requests.len() < MAX_PENDING_REQUESTS,
+            "too many pending requests"
+        );
+        let pending_from_destination = source_requests
+            .iter()
+            .filter_map(|request_id| self.requests.get(request_id))
+            .filter(|request| request.destination == destination)
+            .count();
+        require!(
+            pending_from_destination < MAX_PENDING_REQUESTS_PER_DESTINATION,
+            "too many pending requests from destination"
+        );
+        self.request_index = self
+            .request_index
+            .checked_add(1)
+            .expect("index space exhausted");
+        self.requests.insert(
+            &self.request_index,
+            &StreamRequest {
+                source: source.clone(),
+                destination,
+                flow,
+                end_timestamp: end,
+            },
+        );
+        source_requests.push(self.request_index);
+        self.source_requests.insert(&source, &source_requests);
+        self.request_index
+    }
+
+    /// A pending stream request
+    pub fn get_request(&self, request_id: RequestId) -> Option<StreamRequest> {
+        self.requests.get(&request_id)
+    }
+
+    /// Pending stream requests made of the source
+    pub fn requests_for_source(&self, source: AccountId) -> Vec<RequestId> {
+        self.source_requests.get(&source).unwrap_or_default()
+    }
+
+    /// Accept a stream request creating the subscription, called by the source
+    pub fn accept_request(&mut self, request_id: RequestId) -> Subscription {
+        let request = self.internal_remove_request(request_id);
+        require!(
+            request.source == env::predecessor_account_id(),
+            "caller must be source"
+        );
+        require!(
+            request
+                .end_timestamp
+                .is_none_or(|end_timestamp| end_timestamp > env::block_timestamp()),
+            "end must be in the future"
+        );
+        self.internal_create_subscription(
+            request.source,
+            request.destination,
+            request.flow,
+            request.end_timestamp,
+            env::block_timestamp(),
         )
-        .then(ext_self::wrap_callback(
-            env::signer_account_id(),
-            env::attached_deposit(),
-            env::current_account_id(),
-            0,
-            5_000_000_000_000u64.into(),
-        ))
     }
 
-    /// Unwrap wNEAR and credit the signer the amount in NEAR
-    #[payable]
-    pub fn unwrap_near(&mut self, _amount: Balance) -> Promise {
-        Promise::new(env::current_account_id())
+    /// Reject a stream request, called by the source or withdrawn by the destination
+    pub fn reject_request(&mut self, request_id: RequestId) {
+        let request = self.internal_remove_request(request_id);
+        let caller = env::predecessor_account_id();
+        require!(
+            request.source == caller || request.destination == caller,
+            "caller must be source or destination"
+        );
     }
+}
 
-    pub fn wrap_callback(&mut self, account_id: AccountId, amount: Balance) {
-        assert_eq!(env::promise_results_count(), 1, "This is a callback method");
+impl Paystream {
+    /// Remove a pending stream request
+    fn internal_remove_request(&mut self, request_id: RequestId) -> StreamRequest {
+        let request = self
+            .requests
+            .remove(&request_id)
+            .expect("request not present");
+        let mut source_requests = self
+            .source_requests
+            .get(&request.source)
+            .unwrap_or_default();
+        source_requests.retain(|&pending| pending != request_id);
+        self.source_requests
+            .insert(&request.source, &source_requests);
+        request
+    }
+}
 
-        match env::promise_result(0) {
-            PromiseResult::NotReady => unreachable!(),
-            // TODO what to be done if the cross contract fails
-            PromiseResult::Failed => log!("failed callback"),
-            // Near has been wrapped, update balance of sNEAR for account
-            PromiseResult::Successful(_) => {
-                match self.balances.get(&account_id) {
-                    Some(current_balance) => self
-                        .balances
-                        .insert(&account_id, &current_balance.saturating_add(amount)),
-                    None => self.balances.insert(&account_id, &amount),
+// Relayed transactions
+#[near_bindgen]
+impl Paystream {
+    /// Set the ed25519 key the caller signs payloads relayed on its behalf with
+    pub fn set_relay_key(&mut self, public_key: PublicKey) {
+        require!(
+            public_key.curve_type() == CurveType::ED25519,
+            "relay key must be ed25519"
+        );
+        self.relay_keys
+            .insert(&env::predecessor_account_id(), &public_key);
+    }
+
+    /// The last nonce of a relayed payload used by the account
+    pub fn relay_nonce(&self, account_id: AccountId) -> u64 {
+        self.relay_nonces.get(&account_id).unwrap_or_default()
+    }
+
+    /// Create a subscription signed by its source, submitted by any account.  The signature is
+    /// verified against the relay key of the source, the payload must be signed for this contract
+    /// and the nonce must be greater than the last one it used.
+    pub fn create_subscription_signed(
+        &mut self,
+        payload: SignedCreate,
+        signature: Base64VecU8,
+    ) -> Subscription {
+        let public_key = self
+            .relay_keys
+            .get(&payload.source)
+            .expect("source has no relay key");
+        let public_key = ed25519_dalek::PublicKey::from_bytes(&public_key.as_bytes()[1..])
+            .expect("relay key is valid");
+        let signature =
+            ed25519_dalek::Signature::try_from(&signature.0[..]).expect("signature is malformed");
+        let message = payload.try_to_vec().expect("payload serializes");
+        require!(
+            public_key.verify_strict(&message, &signature).is_ok(),
+            "invalid signature"
+        );
+        require!(
+            payload.contract_id == env::current_account_id(),
+            "payload signed for another contract"
+        );
+        require!(
+            payload.nonce > self.relay_nonce(payload.source.clone()),
+            "nonce already used"
+        );
+        self.relay_nonces.insert(&payload.source, &payload.nonce);
+
+        self.internal_create_subscription_for(
+            payload.source,
+            payload.destination,
+            payload.flow.0,
+            None,
+            env::block_timestamp(),
+        )
+    }
+}
+
+// Audit of subscriptions, only in builds with debug assertions
+#[cfg(debug_assertions)]
+#[near_bindgen]
+impl Paystream {
+    /// If the subscriptions are consistent with the inputs and outputs listing them.  Every
+    /// subscription is listed once in the inputs of its destination and the outputs of its
+    /// source, and every index listed for those accounts and the holders resolves to a
+    /// subscription of the account which isn't beyond the index counter.
+    pub fn check_invariants(&self) -> bool {
+        let mut accounts = vec![];
+        for subscription_index in 1..=self.subscriptions.subscription_index {
+            if let Some(subscription) = self.subscription(subscription_index) {
+                let listed = |lists: &LookupMap<AccountId, Vec<SubscriptionIndex>>,
+                              account_id: &AccountId| {
+                    lists
+                        .get(account_id)
+                        .unwrap_or_default()
+                        .iter()
+                        .filter(|&&listed| listed == subscription_index)
+                        .count()
                 };
+                if listed(&self.subscriptions.inputs, &subscription.destination) != 1
+                    || listed(&self.subscriptions.outputs, &subscription.source) != 1
+                {
+                    return false;
+                }
+                accounts.push(subscription.source);
+                accounts.push(subscription.destination);
             }
         }
+        accounts.extend(self.holders.iter());
+
+        accounts.iter().all(|account_id| {
+            let resolves = |subscription_index: &SubscriptionIndex, is_destination: bool| {
+                *subscription_index <= self.subscriptions.subscription_index
+                    && self
+                        .subscription(*subscription_index)
+                        .is_some_and(|subscription| {
+                            if is_destination {
+                                &subscription.destination == account_id
+                            } else {
+                                &subscription.source == account_id
+                            }
+                        })
+            };
+            let inputs = self
+                .subscriptions
+                .inputs
+                .get(account_id)
+                .unwrap_or_default();
+            let outputs = self
+                .subscriptions
+                .outputs
+                .get(account_id)
+                .unwrap_or_default();
+            inputs.iter().all(|input| resolves(input, true))
+                && outputs.iter().all(|output| resolves(output, false))
+        })
     }
 }
 
+// Conversion between sNEAR and whole NEAR
+#[near_bindgen]
 impl Paystream {
-    /// Try to transfer an amount of sNEAR from source to destination
-    fn try_transfer(
-        &mut self,
-        source: AccountId,
-        destination: AccountId,
-        amount: Balance,
-    ) -> Result<(), &'static str> {
-        let balance_of_source = self.balances.get(&source).ok_or("source doesn't exist")?;
-        let new_balance_of_source = balance_of_source
-            .checked_sub(amount)
-            .ok_or("insufficient balance")?;
+    /// Convert whole NEAR to yocto
+    pub fn to_yocto(&self, near_amount: U128) -> U128 {
+        near_amount
+            .0
+            .checked_mul(10u128.pow(DECIMALS as u32))
+            .expect("amount overflows")
+            .into()
+    }
+
+    /// Convert yocto to whole NEAR, truncating any fraction of NEAR
+    pub fn from_yocto(&self, yocto: U128) -> U128 {
+        (yocto.0 / 10u128.pow(DECIMALS as u32)).into()
+    }
+
+    /// The flow in yoctos per second which streams the NEAR per day, truncating any fraction
+    /// of a yocto
+    pub fn flow_from_near_per_day(&self, near_per_day: U128) -> U128 {
+        (self.to_yocto(near_per_day).0 / SECONDS_PER_DAY as u128).into()
+    }
+}
+
+// Treasury control
+#[near_bindgen]
+impl Paystream {
+    /// Return who the treasurer of the contract is
+    pub fn treasurer(&self) -> &AccountId {
+        &self.treasurer
+    }
+
+    /// Treasurer can be set only by `owner`
+    pub fn set_treasurer(&mut self, new_treasurer: AccountId) {
+        Self::required(self.owner());
+        require!(
+            &new_treasurer != self.treasurer(),
+            "should be new treasurer"
+        );
+        self.treasurer = new_treasurer;
+    }
+
+    /// Return the fee taken by the treasury on settlement
+    pub fn fee_basis_points(&self) -> BasisPoints {
+        self.fee_basis_points
+    }
+
+    /// The fee and the net amount received from settling the amount at the current fee
+    pub fn preview_fee(&self, amount: U128) -> (U128, U128) {
+        let fee = self.fee(amount.0);
+        (fee.into(), (amount.0 - fee).into())
+    }
+
+    /// Fee can be set only by `owner`
+    pub fn set_fee_basis_points(&mut self, fee_basis_points: BasisPoints) {
+        Self::required(self.owner());
+        require!(
+            fee_basis_points <= MAX_BASIS_POINTS,
+            "fee exceeds maximum basis points"
+        );
+        self.fee_basis_points = fee_basis_points;
+    }
+
+    /// How fractions of a yocto are rounded in fees
+    pub fn rounding_mode(&self) -> RoundingMode {
+        self.rounding_mode
+    }
+
+    /// Rounding mode can be set only by `owner`
+    pub fn set_rounding_mode(&mut self, rounding_mode: RoundingMode) {
+        Self::required(self.owner());
+        self.rounding_mode = rounding_mode;
+    }
+
+    /// Fees collected on settlement waiting to be withdrawn by the treasurer
+    pub fn accumulated_fees(&self) -> U128 {
+        self.fees_collected.into()
+    }
+
+    /// Fees collected on the day, counted from the epoch, by settlements made that day
+    pub fn fees_on_day(&self, day_index: u64) -> U128 {
+        self.fees_by_day.get(&day_index).unwrap_or_default().into()
+    }
+
+    /// Withdraw collected fees into the balance of the treasurer, treasurer gated
+    pub fn withdraw_fees(&mut self, amount: U128) {
+        Self::required(self.treasurer());
+        self.fees_collected = self
+            .fees_collected
+            .checked_sub(amount.0)
+            .expect("amount exceeds collected fees");
+        let treasurer = self.treasurer.clone();
+        match self.balances.get(&treasurer) {
+            Some(current_balance) => self
+                .balances
+                .insert(&treasurer, &current_balance.saturating_add(amount.0)),
+            None => self.balances.insert(&treasurer, &amount.0),
+        };
+        self.record_holder(&treasurer);
+    }
+
+    /// Sweep balances below the threshold to the treasurer, owner gated.  Accounts with active
+    /// subscriptions are skipped.  Returns the total swept.
+    pub fn sweep_dust(&mut self, accounts: Vec<AccountId>, threshold: U128) -> U128 {
+        Self::required(self.owner());
+        let treasurer = self.treasurer.clone();
+        let mut swept: Balance = 0;
+        for account_id in accounts {
+            if account_id == treasurer
+                || !self
+                    .subscriptions
+                    .subscriptions_for_account(account_id.clone())
+                    .is_empty()
+            {
+                continue;
+            }
+            let balance = self.balances.get(&account_id).unwrap_or_default();
+            if balance == 0 || balance >= threshold.0 {
+                continue;
+            }
+            self.balances.remove(&account_id);
+            swept = swept.saturating_add(balance);
+            emit_event(
+                "sweep_dust",
+                json!({ "account_id": account_id, "amount": U128(balance) }),
+            );
+        }
+        match self.balances.get(&treasurer) {
+            Some(current_balance) => self
+                .balances
+                .insert(&treasurer, &current_balance.saturating_add(swept)),
+            None => self.balances.insert(&treasurer, &swept),
+        };
+        self.record_holder(&treasurer);
+        swept.into()
+    }
+}
+
+#[near_bindgen]
+impl Paystream {
+    /// Calculate the reserve we would need to be able to create a subscription, from the balance
+    /// not already locked by the reserves of the account's other subscriptions.  `replaced` is
+    /// the reserve of a subscription whose flow is being changed, it doesn't count against it.
+    fn sufficient_reserve(&self, rate: YoctosPerSecond, account_id: &AccountId, replaced: Balance) {
+        let current_balance = self
+            .balances
+            .get(account_id)
+            .expect("that source has balance");
+        let locked_reserve = self.locked_reserve_of(account_id).saturating_sub(replaced);
+        require!(
+            current_balance.saturating_sub(locked_reserve) > self.minimum_balance(rate),
+            "sufficient reserve is required"
+        );
+    }
+
+    /// The minimum balance required to hold the reserve for a rate
+    fn minimum_balance(&self, rate: YoctosPerSecond) -> Balance {
+        Flow(rate)
+            .checked_over(self.reserve_seconds_for_flow(rate).0)
+            .expect("reserve overflows")
+            .0
+    }
+
+    /// The reserve window a flow requires, from the highest tier the flow reaches
+    pub fn reserve_seconds_for_flow(&self, flow: YoctosPerSecond) -> U64 {
+        self.reserve_tiers
+            .iter()
+            .rev()
+            .find(|(threshold, _)| flow >= *threshold)
+            .map_or(self.reserve, |(_, reserve)| *reserve)
+            .into()
+    }
+
+    /// Update the reserve windows required of flows at or above each threshold, owner gated
+    pub fn update_reserve_tiers(&mut self, reserve_tiers: Vec<(YoctosPerSecond, Seconds)>) {
+        Self::required(self.owner());
+        let mut reserve_tiers = reserve_tiers;
+        reserve_tiers.sort_by_key(|(threshold, _)| *threshold);
+        self.reserve_tiers = reserve_tiers;
+    }
+
+    /// How long after creation the source may claw a subscription back
+    pub fn clawback_seconds(&self) -> U64 {
+        self.clawback_seconds.into()
+    }
+
+    /// Update the clawback window, owner gated
+    pub fn set_clawback_seconds(&mut self, clawback_seconds: Seconds) {
+        Self::required(self.owner());
+        self.clawback_seconds = clawback_seconds;
+    }
+
+    /// Update the reserve stored in the contract, owner gated
+    pub fn update_reserve(&mut self, reserve: Seconds) {
+        Self::required(self.owner());
+        self.reserve = reserve;
+    }
+
+    /// Update the reserve stored in the contract and audit existing subscriptions against it,
+    /// owner gated.  Subscriptions are visited in index order starting at `from_index`, at most
+    /// `limit` indices, call again from `from_index` plus `limit` to audit the rest.  Returns the
+    /// subscriptions whose source no longer meets the reserve, these are left active for the
+    /// owner to act on.
+    pub fn update_reserve_and_audit(
+        &mut self,
+        reserve: Seconds,
+        from_index: SubscriptionIndex,
+        limit: u64,
+    ) -> Vec<SubscriptionIndex> {
+        self.update_reserve(reserve);
+        let to_index = from_index
+            .saturating_add(limit)
+            .min(self.subscriptions.subscription_index.saturating_add(1));
+        (from_index.max(1)..to_index)
+            .filter(|subscription_index| {
+                self.subscription(*subscription_index)
+                    .map(|subscription| {
+                        self.balances.get(&subscription.source).unwrap_or_default()
+                            <= self.minimum_balance(subscription.flow)
+                    })
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
+
+    /// Update the minimum flow for subscriptions, owner gated
+    pub fn update_min_flow(&mut self, min_flow: YoctosPerSecond) {
+        Self::required(self.owner());
+        self.min_flow = min_flow;
+    }
+
+    /// Update the maximum subscriptions an account may be the source or destination of, owner
+    /// gated
+    pub fn update_max_subscriptions_per_account(&mut self, max_subscriptions_per_account: u32) {
+        Self::required(self.owner());
+        self.max_subscriptions_per_account = max_subscriptions_per_account;
+    }
+
+    /// Update the cooldown between subscriptions created by a source, owner gated
+    pub fn update_create_cooldown(&mut self, create_cooldown: Seconds) {
+        Self::required(self.owner());
+        self.create_cooldown = create_cooldown;
+    }
+
+    /// Create a subscription.  If the subscription meets the reserve requirements for the caller
+    /// we create it and payment started from this moment.
+    pub fn create_subscription(
+        &mut self,
+        source: AccountId,
+        destination: AccountId,
+        rate: YoctosPerSecond,
+    ) -> Subscription {
+        self.internal_create_subscription(source, destination, rate, None, env::block_timestamp())
+    }
+
+    /// Create a subscription from the caller streaming the amount of yoctos per unit of time,
+    /// converted to a flow per second
+    pub fn create_subscription_with_unit(
+        &mut self,
+        destination: AccountId,
+        amount: U128,
+        unit: FlowUnit,
+    ) -> Subscription {
+        self.internal_create_subscription(
+            env::predecessor_account_id(),
+            destination,
+            unit.to_flow(amount.0),
+            None,
+            env::block_timestamp(),
+        )
+    }
+
+    /// Create a subscription from the caller which streams at the rate until the total cap has
+    /// been settled, when it completes
+    pub fn create_capped_subscription(
+        &mut self,
+        destination: AccountId,
+        flow: YoctosPerSecond,
+        total_cap: U128,
+    ) -> Subscription {
+        require!(total_cap.0 > 0, "cap needs to be greater than zero");
+        let mut subscription = self.internal_create_subscription(
+            env::predecessor_account_id(),
+            destination,
+            flow,
+            None,
+            env::block_timestamp(),
+        );
+        let subscription_index = self.subscriptions.subscription_index;
+        self.untrack(subscription_index, &subscription);
+        subscription.cap = Some(total_cap.0);
+        let subscription = self
+            .subscriptions
+            .try_replace(subscription_index, subscription)
+            .unwrap();
+        self.track(subscription_index, &subscription);
+        subscription
+    }
+
+    /// Create a subscription from the caller which only accrues while the price reported by the
+    /// oracle meets the condition.  Nothing accrues until `poke` has found the condition holds.
+    pub fn create_conditional_subscription(
+        &mut self,
+        destination: AccountId,
+        flow: YoctosPerSecond,
+        oracle: AccountId,
+        condition: PriceCondition,
+    ) -> Subscription {
+        let mut subscription = self.internal_create_subscription(
+            env::predecessor_account_id(),
+            destination,
+            flow,
+            None,
+            env::block_timestamp(),
+        );
+        let subscription_index = self.subscriptions.subscription_index;
+        self.untrack(subscription_index, &subscription);
+        subscription.condition = Some(OracleCondition {
+            oracle,
+            condition,
+            holds: false,
+        });
+        let subscription = self
+            .subscriptions
+            .try_replace(subscription_index, subscription)
+            .unwrap();
+        self.track(subscription_index, &subscription);
+        subscription
+    }
+
+    /// Create a subscription from the caller streaming yoctos per `FLOW_SCALE` seconds, for
+    /// streams slower than a yocto a second.  The flow is validated and reserved rounded up to
+    /// whole yoctos a second.
+    pub fn create_subscription_scaled(
+        &mut self,
+        destination: AccountId,
+        scaled_flow: U128,
+    ) -> Subscription {
+        require!(scaled_flow.0 > 0, "rate needs to be greater than zero");
+        let reserved_flow = (scaled_flow.0 - 1) / FLOW_SCALE + 1;
+        let mut subscription = self.internal_create_subscription(
+            env::predecessor_account_id(),
+            destination,
+            reserved_flow,
+            None,
+            env::block_timestamp(),
+        );
+        let subscription_index = self.subscriptions.subscription_index;
+        self.untrack(subscription_index, &subscription);
+        subscription.flow = scaled_flow.0 / FLOW_SCALE;
+        subscription.scaled_flow = Some(scaled_flow.0);
+        self.committed_flow = self
+            .committed_flow
+            .saturating_sub(reserved_flow)
+            .saturating_add(subscription.flow);
+        self.flow_history.insert(
+            &subscription_index,
+            &vec![(env::block_timestamp(), subscription.flow)],
+        );
+        let subscription = self
+            .subscriptions
+            .try_replace(subscription_index, subscription)
+            .unwrap();
+        self.track(subscription_index, &subscription);
+        subscription
+    }
+
+    /// Create an empty group owned by the caller, returning its id
+    pub fn create_group(&mut self) -> GroupId {
+        self.group_index += 1;
+        self.group_owners
+            .insert(&self.group_index, &env::predecessor_account_id());
+        self.groups.insert(&self.group_index, &vec![]);
+        self.group_index
+    }
+
+    /// Place a subscription of the caller's in a group the caller owns
+    pub fn add_to_group(&mut self, group_id: GroupId, subscription_index: SubscriptionIndex) {
+        require!(
+            self.group_owners.get(&group_id) == Some(env::predecessor_account_id()),
+            "caller must own group"
+        );
+        let mut subscription = self.try_subscription(subscription_index).unwrap();
+        require!(
+            subscription.source == env::predecessor_account_id(),
+            "caller must be source"
+        );
+        require!(
+            subscription.group_id.is_none(),
+            "subscription already in a group"
+        );
+        subscription.group_id = Some(group_id);
+        self.subscriptions
+            .try_replace(subscription_index, subscription)
+            .unwrap();
+        let mut members = self.groups.get(&group_id).unwrap_or_default();
+        members.push(subscription_index);
+        self.groups.insert(&group_id, &members);
+    }
+
+    /// Remove every subscription in the group, settling each as `remove_subscription` does, and
+    /// the group itself once empty.  Subscriptions still within their reserve lock are skipped
+    /// and stay in the group, call again once they unlock.  Returns the number of subscriptions
+    /// removed.
+    pub fn remove_group(&mut self, group_id: GroupId) -> u64 {
+        require!(
+            self.group_owners.get(&group_id) == Some(env::predecessor_account_id()),
+            "caller must own group"
+        );
+        let members = self.groups.get(&group_id).unwrap_or_default();
+        let mut removed = 0;
+        for subscription_index in members.iter() {
+            let unlocked = self
+                .subscription(*subscription_index)
+                .is_some_and(|subscription| {
+                    env::block_timestamp() >= subscription.reserve_unlocked_at()
+                });
+            if unlocked {
+                self.remove_subscription(*subscription_index);
+                removed += 1;
+            }
+        }
+        if self.groups.get(&group_id).unwrap_or_default().is_empty() {
+            self.groups.remove(&group_id);
+            self.group_owners.remove(&group_id);
+        }
+        removed
+    }
+
+    /// Forward the share in basis points of all that settles on the incoming subscription to the
+    /// onward destination, called by its destination.  The share moves as it settles, into the
+    /// onward destination's claimable or balance as the incoming subscription settles to the
+    /// caller.  Replaces any earlier passthrough of the subscription.
+    pub fn create_passthrough(
+        &mut self,
+        incoming_index: SubscriptionIndex,
+        onward_destination: AccountId,
+        fraction_bps: BasisPoints,
+    ) {
+        let subscription = self.try_subscription(incoming_index).unwrap();
+        require!(
+            subscription.destination == env::predecessor_account_id(),
+            "caller must be destination"
+        );
+        require!(
+            fraction_bps <= MAX_BASIS_POINTS,
+            "fraction must not exceed 10000 basis points"
+        );
+        require!(
+            onward_destination != subscription.destination,
+            "onward destination must not be destination"
+        );
+        self.passthroughs
+            .insert(&incoming_index, &(onward_destination, fraction_bps));
+    }
+
+    /// Create a subscription from the caller for the duration, locking all it will stream from
+    /// the caller's balance up front.  Settlement draws on the escrow so the destination is paid
+    /// whatever the caller's balance, and what hasn't streamed is returned on removal.
+    pub fn create_escrowed_subscription(
+        &mut self,
+        destination: AccountId,
+        flow: YoctosPerSecond,
+        duration: Seconds,
+    ) -> Subscription {
+        require!(duration > 0, "duration needs to be greater than zero");
+        let source = env::predecessor_account_id();
+        let escrow = Flow(flow)
+            .checked_over(duration)
+            .expect("escrow overflows")
+            .0;
+        let subscription = self.internal_create_subscription(
+            source.clone(),
+            destination,
+            flow,
+            Some(env::block_timestamp().saturating_add(duration)),
+            env::block_timestamp(),
+        );
+        let balance = self.balances.get(&source).unwrap_or_default();
+        require!(balance >= escrow, "insufficient balance");
+        self.balances.insert(&source, &(balance - escrow));
+        self.escrows
+            .insert(&self.subscriptions.subscription_index, &escrow);
+        subscription
+    }
+
+    /// Create a subscription with a free trial.  Nothing accrues for the trial period, after
+    /// which the stream is paid at the rate.
+    pub fn create_trial_subscription(
+        &mut self,
+        source: AccountId,
+        destination: AccountId,
+        rate: YoctosPerSecond,
+        trial_seconds: Seconds,
+    ) -> Subscription {
+        let paid_from = env::block_timestamp().saturating_add(trial_seconds);
+        self.internal_create_subscription(source, destination, rate, None, paid_from)
+    }
+
+    /// Create a subscription from the caller which starts streaming at `start_at`, nothing
+    /// accrues before then.  The reserve is required from creation.
+    pub fn create_subscription_starting(
+        &mut self,
+        destination: AccountId,
+        flow: YoctosPerSecond,
+        start_at: Seconds,
+    ) -> Subscription {
+        require!(
+            start_at >= env::block_timestamp(),
+            "start must not be in the past"
+        );
+        self.internal_create_subscription(
+            env::predecessor_account_id(),
+            destination,
+            flow,
+            None,
+            start_at,
+        )
+    }
+
+    /// Create a subscription which stops streaming at the end timestamp.  The same reserve
+    /// requirements apply as for an open ended subscription.
+    pub fn create_subscription_until(
+        &mut self,
+        source: AccountId,
+        destination: AccountId,
+        rate: YoctosPerSecond,
+        end_timestamp: Seconds,
+    ) -> Subscription {
+        require!(
+            end_timestamp > env::block_timestamp(),
+            "end must be in the future"
+        );
+        self.internal_create_subscription(
+            source,
+            destination,
+            rate,
+            Some(end_timestamp),
+            env::block_timestamp(),
+        )
+    }
+
+    /// Save a template for the caller, overwriting any template with the same name
+    pub fn save_template(
+        &mut self,
+        name: String,
+        flow: YoctosPerSecond,
+        end_offset: Option<Seconds>,
+    ) -> Template {
+        let template = Template { flow, end_offset };
+        self.templates
+            .insert(&(env::predecessor_account_id(), name), &template);
+        template
+    }
+
+    /// A template saved by the account
+    pub fn get_template(&self, account_id: AccountId, name: String) -> Option<Template> {
+        self.templates.get(&(account_id, name))
+    }
+
+    /// Create a subscription from the caller to destination from a saved template
+    pub fn create_from_template(&mut self, name: String, destination: AccountId) -> Subscription {
+        let source = env::predecessor_account_id();
+        let template = self
+            .templates
+            .get(&(source.clone(), name))
+            .expect("template not present");
+        let end_timestamp = template
+            .end_offset
+            .map(|end_offset| env::block_timestamp().saturating_add(end_offset));
+        self.internal_create_subscription(
+            source,
+            destination,
+            template.flow,
+            end_timestamp,
+            env::block_timestamp(),
+        )
+    }
+
+    /// Schedule a single payment of the amount from the caller to the destination, paid in full
+    /// once the unlock time is reached.  The amount is held in escrow from now, so it is paid
+    /// whatever the caller's balance at unlock, and returned if the payment is removed first.
+    pub fn create_scheduled_payment(
+        &mut self,
+        destination: AccountId,
+        amount: U128,
+        unlock_at: Seconds,
+    ) -> Subscription {
+        let source = env::predecessor_account_id();
+        self.require_can_create(&source, &destination);
+        require!(amount.0 > 0, "amount needs to be greater than zero");
+        require!(
+            unlock_at > env::block_timestamp(),
+            "unlock must be in the future"
+        );
+        require!(
+            self.balances.get(&source).unwrap_or_default() >= amount.0,
+            "insufficient balance"
+        );
+
+        self.last_create.insert(&source, &env::block_timestamp());
+        self.register_destination(&destination);
+        self.active_subscriptions += 1;
+        let mut subscription =
+            self.subscriptions
+                .create(source, destination, 0, None, env::block_timestamp());
+        subscription.lump_sum = amount.0;
+        subscription.unlock_at = unlock_at;
+        subscription.frozen_offset = self.creation_frozen_offset();
+        subscription.active = !self
+            .acknowledgment_required
+            .contains(&subscription.destination);
+        let subscription_index = self.subscriptions.subscription_index;
+        let subscription = self
+            .subscriptions
+            .try_replace(subscription_index, subscription)
+            .unwrap();
+        self.track(subscription_index, &subscription);
+        let balance = self.balances.get(&subscription.source).unwrap_or_default();
+        self.balances
+            .insert(&subscription.source, &(balance - amount.0));
+        self.escrows.insert(&subscription_index, &amount.0);
+        subscription
+    }
+
+    /// Validate and create a subscription for the caller
+    fn internal_create_subscription(
+        &mut self,
+        source: AccountId,
+        destination: AccountId,
+        rate: YoctosPerSecond,
+        end_timestamp: Option<Seconds>,
+        paid_from: Seconds,
+    ) -> Subscription {
+        require!(
+            source == env::predecessor_account_id(),
+            "caller must be source"
+        );
+        self.internal_create_subscription_for(source, destination, rate, end_timestamp, paid_from)
+    }
+
+    /// Validate and create a subscription for the source, who has been authorized by the caller
+    fn internal_create_subscription_for(
+        &mut self,
+        source: AccountId,
+        destination: AccountId,
+        rate: YoctosPerSecond,
+        end_timestamp: Option<Seconds>,
+        paid_from: Seconds,
+    ) -> Subscription {
+        self.require_can_create(&source, &destination);
+        require!(rate > 0, "rate needs to be greater than zero");
+        require!(rate >= self.min_flow, "flow below minimum");
+        self.require_within_incoming_cap(&destination, 0, rate);
+        let timestamp = env::block_timestamp();
+        // Validate that we have enough in the account to create the subscription(reserve)
+        self.sufficient_reserve(rate, &source, 0);
+        self.last_create.insert(&source, &timestamp);
+        self.register_destination(&destination);
+        self.active_subscriptions += 1;
+        self.committed_flow = self.committed_flow.saturating_add(rate);
+        let mut subscription =
+            self.subscriptions
+                .create(source, destination, rate, end_timestamp, paid_from);
+        subscription.locked_reserve = self.minimum_balance(rate);
+        self.lock_reserve(&subscription.source, subscription.locked_reserve);
+        subscription.frozen_offset = self.creation_frozen_offset();
+        subscription.active = !self
+            .acknowledgment_required
+            .contains(&subscription.destination);
+        let subscription_index = self.subscriptions.subscription_index;
+        self.flow_history
+            .insert(&subscription_index, &vec![(timestamp, rate)]);
+        let subscription = self
+            .subscriptions
+            .try_replace(subscription_index, subscription)
+            .unwrap();
+        self.track(subscription_index, &subscription);
+        subscription
+    }
+
+    /// Check the source may create a subscription of any kind to the destination now, whatever
+    /// it streams
+    fn require_can_create(&self, source: &AccountId, destination: &AccountId) {
+        require!(
+            self.shutdown_phase == ShutdownPhase::Running,
+            "contract is shutting down"
+        );
+        require!(source != destination, "source must not be destination");
+        require!(
+            !self.frozen.contains(source) && !self.frozen.contains(destination),
+            "account is frozen"
+        );
+        require!(
+            self.is_allowed_destination(source.clone(), destination.clone()),
+            "destination not allowed"
+        );
+        if let Some(last_create) = self.last_create.get(source) {
+            require!(
+                env::block_timestamp() >= last_create.saturating_add(self.create_cooldown),
+                "create cooldown has not elapsed"
+            );
+        }
+        let max_subscriptions = self.max_subscriptions_per_account as usize;
+        require!(
+            self.subscriptions
+                .outputs
+                .get(source)
+                .unwrap_or_default()
+                .len()
+                < max_subscriptions
+                && self
+                    .subscriptions
+                    .inputs
+                    .get(destination)
+                    .unwrap_or_default()
+                    .len()
+                    < max_subscriptions,
+            "subscription limit reached"
+        );
+    }
+
+    /// Remove subscription.  The caller may be the source or destination of the subscription.
+    /// On removal the stream is settled at this moment in time, the stream from then would have
+    /// stopped.  If the source can't cover the settlement the destination receives what the
+    /// source can afford.  The source may only remove the subscription once half of the reserve
+    /// window has elapsed, the destination may remove it at any time.
+    pub fn remove_subscription(&mut self, subscription_index: SubscriptionIndex) -> Subscription {
+        let subscription = self.try_subscription(subscription_index).unwrap();
+        let role = subscription.role(&env::predecessor_account_id());
+        require!(role.is_some(), "caller must be source or destination");
+        if role == Some("source") {
+            require!(
+                env::block_timestamp() >= subscription.reserve_unlocked_at(),
+                "reserve lock period has not elapsed"
+            );
+        }
+
+        let mut subscription = self
+            .subscriptions
+            .try_remove(subscription_index)
+            .map(|subscription| self.thawed(subscription))
+            .expect("subscription is removed");
+        self.untrack(subscription_index, &subscription);
+        self.active_subscriptions -= 1;
+        self.committed_flow = self.committed_flow.saturating_sub(subscription.flow);
+
+        let accrued = subscription.settle(self.accrual_timestamp());
+        self.draw_escrow(subscription_index, &subscription.source, accrued);
+        let amount = accrued.min(self.balances.get(&subscription.source).unwrap_or_default());
+        self.try_transfer(
+            subscription.source.clone(),
+            subscription.destination.clone(),
+            amount,
+            subscription.pull,
+        )
+        .expect("transfer on settlement");
+        subscription.total_settled = subscription.total_settled.saturating_add(amount);
+        self.distribute_settlement(subscription_index, &subscription, amount);
+        self.forget_subscription(subscription_index, &subscription);
+        Self::warn_insolvency(&subscription.source, accrued - amount);
+        self.notify_settlement(subscription_index, &subscription, amount);
+
+        subscription
+    }
+
+    /// Reverse a subscription sent by mistake, called by the source within `clawback_seconds` of
+    /// creation.  The subscription is removed without settling what has accrued since it was
+    /// last settled, and what its settlements credited, to the destination and to any account
+    /// the destination passed a share on to, is returned to the source.  Fails if any of them
+    /// no longer holds it.  Fees already collected are not returned.
+    pub fn clawback_subscription(&mut self, subscription_index: SubscriptionIndex) -> Subscription {
+        let subscription = self.try_subscription(subscription_index).unwrap();
+        require!(
+            subscription.source == env::predecessor_account_id(),
+            "caller must be source"
+        );
+        require!(
+            env::block_timestamp()
+                < subscription
+                    .created_at
+                    .saturating_add(self.clawback_seconds),
+            "clawback window has passed"
+        );
+
+        let credits = self
+            .settlement_credits
+            .get(&subscription_index)
+            .unwrap_or_default();
+        let mut received: Balance = 0;
+        for (account_id, pull, amount) in credits.iter() {
+            let credited = if *pull {
+                &mut self.claimable
+            } else {
+                &mut self.balances
+            };
+            let held = credited.get(account_id).unwrap_or_default();
+            require!(held >= *amount, "settled funds have been withdrawn");
+            credited.insert(account_id, &(held - amount));
+            received = received.saturating_add(*amount);
+        }
+
+        let subscription = self
+            .subscriptions
+            .try_remove(subscription_index)
+            .map(|subscription| self.thawed(subscription))
+            .expect("subscription is removed");
+        self.untrack(subscription_index, &subscription);
+        self.active_subscriptions -= 1;
+        self.committed_flow = self.committed_flow.saturating_sub(subscription.flow);
+        let balance = self.balances.get(&subscription.source).unwrap_or_default();
+        self.balances
+            .insert(&subscription.source, &balance.saturating_add(received));
+        self.forget_subscription(subscription_index, &subscription);
+
+        subscription
+    }
+
+    /// Remove the subscription as `remove_subscription` does, returning the error rather than
+    /// panicking if it isn't present
+    pub fn try_remove_subscription(
+        &mut self,
+        subscription_index: SubscriptionIndex,
+    ) -> SubscriptionResult {
+        self.try_subscription(subscription_index)?;
+        Ok(self.remove_subscription(subscription_index))
+    }
+
+    /// Remove up to `limit` subscriptions between the caller and the counterparty in either
+    /// direction, settling each as `remove_subscription` does.  Returns the number removed.
+    pub fn cancel_with_counterparty(&mut self, counterparty: AccountId, limit: u64) -> u64 {
+        let caller = env::predecessor_account_id();
+        let subscription_indexes: Vec<SubscriptionIndex> = self
+            .subscriptions
+            .subscriptions_for_account(caller)
+            .into_iter()
+            .filter(|subscription_index| {
+                self.subscription(*subscription_index)
+                    .is_some_and(|subscription| subscription.role(&counterparty).is_some())
+            })
+            .take(limit as usize)
+            .collect();
+        for subscription_index in subscription_indexes.iter() {
+            self.remove_subscription(*subscription_index);
+        }
+        subscription_indexes.len() as u64
+    }
+
+    /// Remove subscription only if the source can settle all that has accrued, otherwise the
+    /// subscription is left intact.
+    pub fn remove_subscription_strict(
+        &mut self,
+        subscription_index: SubscriptionIndex,
+    ) -> Subscription {
+        let subscription = self.try_subscription(subscription_index).unwrap();
+        require!(
+            self.balances.get(&subscription.source).unwrap_or_default()
+                >= self.uncovered_by_escrow(
+                    subscription_index,
+                    subscription.accrued(self.accrual_timestamp())
+                ),
+            "cannot settle, insolvent source"
+        );
+        self.remove_subscription(subscription_index)
+    }
+
+    /// Settle as much of the subscription as the source can afford.  The stream is settled for
+    /// the time the payment covers, the remainder is left to accrue and returned as the shortfall.
+    pub fn settle_partial(&mut self, subscription_index: SubscriptionIndex) -> U128 {
+        let subscription = self.try_subscription(subscription_index).unwrap();
+        require!(
+            subscription.role(&env::predecessor_account_id()).is_some(),
+            "caller must be source or destination"
+        );
+        self.internal_settle_partial(subscription_index, subscription)
+            .into()
+    }
+
+    /// Settle as `settle_partial` does unless the nonce isn't greater than the last one processed
+    /// for the subscription, so a retried transaction doesn't settle twice however the retries
+    /// are interleaved.  Returns the shortfall or `None` when the nonce is replayed.
+    pub fn settle_with_nonce(
+        &mut self,
+        subscription_index: SubscriptionIndex,
+        nonce: u64,
+    ) -> Option<U128> {
+        if self
+            .settlement_nonces
+            .get(&subscription_index)
+            .is_some_and(|last_nonce| nonce <= last_nonce)
+        {
+            return None;
+        }
+        let shortfall = self.settle_partial(subscription_index);
+        // Nothing is kept for a subscription which completed on settlement
+        if self.subscriptions.exists(subscription_index) {
+            self.settlement_nonces.insert(&subscription_index, &nonce);
+        }
+        Some(shortfall)
+    }
+
+    /// Settle as much of the subscription as the source can afford, returning the shortfall
+    fn internal_settle_partial(
+        &mut self,
+        subscription_index: SubscriptionIndex,
+        mut subscription: Subscription,
+    ) -> Balance {
+        self.untrack(subscription_index, &subscription);
+
+        let timestamp = self.accrual_timestamp();
+        let accrued = subscription.accrued(timestamp);
+        self.draw_escrow(subscription_index, &subscription.source, accrued);
+        let affordable = self
+            .balances
+            .get(&subscription.source)
+            .unwrap_or_default()
+            .min(accrued);
+        let time_paid = subscription.time_paid_by(affordable);
+        // A lump sum is only paid in full
+        let amount = if affordable == accrued {
+            subscription.settle(timestamp)
+        } else {
+            // Nothing was owed for a trial, payment covers the stream from where it's paid
+            subscription.timestamp = subscription
+                .timestamp
+                .max(subscription.paid_from)
+                .saturating_add(time_paid);
+            subscription.paid_for(time_paid)
+        };
+
+        self.try_transfer(
+            subscription.source.clone(),
+            subscription.destination.clone(),
+            amount,
+            subscription.pull,
+        )
+        .expect("transfer on settlement");
+        subscription.total_settled = subscription.total_settled.saturating_add(amount);
+        self.distribute_settlement(subscription_index, &subscription, amount);
+        Self::warn_insolvency(&subscription.source, accrued - amount);
+        self.notify_settlement(subscription_index, &subscription, amount);
+        if subscription.is_complete() {
+            self.subscriptions
+                .try_remove(subscription_index)
+                .map(|subscription| self.thawed(subscription))
+                .expect("subscription is removed");
+            self.forget_subscription(subscription_index, &subscription);
+            self.active_subscriptions -= 1;
+            self.committed_flow = self.committed_flow.saturating_sub(subscription.flow);
+        } else {
+            self.track(subscription_index, &subscription);
+            self.subscriptions
+                .try_replace(subscription_index, subscription)
+                .unwrap();
+        }
+
+        accrued - amount
+    }
+
+    /// The flow the destination receives per second once the fee is taken
+    pub fn net_flow_to_destination(&self, subscription_index: SubscriptionIndex) -> U128 {
+        let subscription = self.try_subscription(subscription_index).unwrap();
+        subscription
+            .flow
+            .saturating_sub(self.fee(subscription.flow))
+            .into()
+    }
+
+    /// Change the end time of a subscription, `None` streams without end.  The source may extend
+    /// the subscription, shortening it requires the destination to have approved the new end time
+    /// with `approve_end_time`.  The stream is settled up to this moment before the change.
+    pub fn set_end_time(
+        &mut self,
+        subscription_index: SubscriptionIndex,
+        new_end: Option<Seconds>,
+    ) -> Subscription {
+        let mut subscription = self.try_subscription(subscription_index).unwrap();
+        require!(
+            subscription.source == env::predecessor_account_id(),
+            "caller must be source"
+        );
+        require!(
+            !self.escrows.contains_key(&subscription_index),
+            "subscription is escrowed"
+        );
+        require!(
+            new_end.is_none_or(|new_end| new_end > env::block_timestamp()),
+            "end must be in the future"
+        );
+
+        let extends = match (subscription.end_timestamp, new_end) {
+            (_, None) => true,
+            (None, Some(_)) => false,
+            (Some(end_timestamp), Some(new_end)) => new_end >= end_timestamp,
+        };
+        if !extends {
+            require!(
+                self.end_time_approvals.remove(&subscription_index) == new_end,
+                "destination must approve shortening"
+            );
+        }
+
+        self.untrack(subscription_index, &subscription);
+        let amount = subscription.settle(self.accrual_timestamp());
+        self.draw_escrow(subscription_index, &subscription.source, amount);
+        self.try_transfer(
+            subscription.source.clone(),
+            subscription.destination.clone(),
+            amount,
+            subscription.pull,
+        )
+        .expect("transfer on settlement");
+        subscription.total_settled = subscription.total_settled.saturating_add(amount);
+        self.distribute_settlement(subscription_index, &subscription, amount);
+
+        subscription.end_timestamp = new_end;
+        self.track(subscription_index, &subscription);
+        self.subscriptions
+            .try_replace(subscription_index, subscription)
+            .unwrap()
+    }
+
+    /// Notify the account of each settlement of the subscription, called by the destination.
+    /// `None` falls back to the destination's notification target.
+    pub fn set_notify(
+        &mut self,
+        subscription_index: SubscriptionIndex,
+        notify: Option<AccountId>,
+    ) -> Subscription {
+        let mut subscription = self.try_subscription(subscription_index).unwrap();
+        require!(
+            subscription.destination == env::predecessor_account_id(),
+            "caller must be destination"
+        );
+        subscription.notify = notify;
+        self.subscriptions
+            .try_replace(subscription_index, subscription)
+            .unwrap()
+    }
+
+    /// Choose whether settlement waits to be claimed or is pushed into the balance, called by the
+    /// destination.  The stream is settled up to this moment in the previous mode.
+    pub fn set_settlement_mode(
+        &mut self,
+        subscription_index: SubscriptionIndex,
+        pull: bool,
+    ) -> Subscription {
+        let subscription = self.try_subscription(subscription_index).unwrap();
+        require!(
+            subscription.destination == env::predecessor_account_id(),
+            "caller must be destination"
+        );
+        self.internal_settle_partial(subscription_index, subscription);
+
+        let mut subscription = self.try_subscription(subscription_index).unwrap();
+        subscription.pull = pull;
+        self.subscriptions
+            .try_replace(subscription_index, subscription)
+            .unwrap()
+    }
+
+    /// Acknowledge a subscription, called by the destination, so it accrues from now
+    pub fn acknowledge_subscription(
+        &mut self,
+        subscription_index: SubscriptionIndex,
+    ) -> Subscription {
+        let mut subscription = self.try_subscription(subscription_index).unwrap();
+        require!(
+            subscription.destination == env::predecessor_account_id(),
+            "caller must be destination"
+        );
+        require!(!subscription.active, "subscription already active");
+
+        self.untrack(subscription_index, &subscription);
+        subscription.active = true;
+        // A scheduled payment keeps its timestamp so it is still paid if already unlocked
+        if subscription.lump_sum == 0 {
+            subscription.timestamp = self.accrual_timestamp();
+        }
+        let subscription = self
+            .subscriptions
+            .try_replace(subscription_index, subscription)
+            .unwrap();
+        self.track(subscription_index, &subscription);
+        subscription
+    }
+
+    /// Approve the source shortening the subscription to the end time, called by the destination
+    pub fn approve_end_time(&mut self, subscription_index: SubscriptionIndex, new_end: Seconds) {
+        let subscription = self.try_subscription(subscription_index).unwrap();
+        require!(
+            subscription.destination == env::predecessor_account_id(),
+            "caller must be destination"
+        );
+        self.end_time_approvals
+            .insert(&subscription_index, &new_end);
+    }
+
+    /// Transfer the right to receive a subscription to a new destination.  Only the destination
+    /// may transfer the subscription, the stream is settled to the current destination at this
+    /// moment in time and from then accrues to the new destination.
+    pub fn transfer_incoming(
+        &mut self,
+        subscription_index: SubscriptionIndex,
+        new_destination: AccountId,
+    ) -> Subscription {
+        let mut subscription = self.try_subscription(subscription_index).unwrap();
+        require!(
+            subscription.destination == env::predecessor_account_id(),
+            "caller must be destination"
+        );
+        require!(
+            subscription.destination != new_destination,
+            "should be new destination"
+        );
+        require!(
+            subscription.source != new_destination,
+            "source must not be destination"
+        );
+        require!(!self.frozen.contains(&new_destination), "account is frozen");
+        require!(
+            self.is_allowed_destination(subscription.source.clone(), new_destination.clone()),
+            "destination not allowed"
+        );
+        self.require_within_incoming_cap(&new_destination, 0, subscription.flow);
+        require!(
+            self.subscriptions
+                .inputs
+                .get(&new_destination)
+                .unwrap_or_default()
+                .len()
+                < self.max_subscriptions_per_account as usize,
+            "subscription limit reached"
+        );
+
+        self.untrack(subscription_index, &subscription);
+        let amount = subscription.settle(self.accrual_timestamp());
+        self.draw_escrow(subscription_index, &subscription.source, amount);
+        self.try_transfer(
+            subscription.source.clone(),
+            subscription.destination.clone(),
+            amount,
+            subscription.pull,
+        )
+        .expect("transfer on settlement");
+        subscription.total_settled = subscription.total_settled.saturating_add(amount);
+        self.distribute_settlement(subscription_index, &subscription, amount);
+
+        self.register_destination(&new_destination);
+        self.passthroughs.remove(&subscription_index);
+        // What the old destination chose or approved doesn't carry over to the new one
+        self.end_time_approvals.remove(&subscription_index);
+        subscription.notify = None;
+        subscription.pull = true;
+        subscription.active = !self.acknowledgment_required.contains(&new_destination);
+        subscription.destination = new_destination;
+        self.track(subscription_index, &subscription);
+        self.subscriptions
+            .try_update_destination(subscription_index, subscription)
+            .unwrap()
+    }
+
+    /// The balance of the account as stored, without what has accrued since its subscriptions
+    /// were last settled.  `ft_balance_of` includes what has accrued.
+    pub fn stored_balance(&self, account_id: AccountId) -> U128 {
+        self.balances.get(&account_id).unwrap_or_default().into()
+    }
+
+    /// Funds still locked in escrow for the subscription, zero if it isn't escrowed
+    pub fn escrow_balance(&self, subscription_index: SubscriptionIndex) -> U128 {
+        self.escrows
+            .get(&subscription_index)
+            .unwrap_or_default()
+            .into()
+    }
+
+    /// Settled sNEAR waiting to be claimed by the account
+    pub fn claimable_of(&self, account_id: AccountId) -> U128 {
+        self.claimable.get(&account_id).unwrap_or_default().into()
+    }
+
+    /// The current balance of the account together with what it has waiting to be claimed
+    pub fn total_available(&self, account_id: AccountId) -> U128 {
+        let claimable = self.claimable.get(&account_id).unwrap_or_default();
+        self.current_balance(account_id)
+            .0
+            .checked_add(claimable)
+            .expect("balance overflows")
+            .into()
+    }
+
+    /// Balance of the account less the reserves locked by its outgoing subscriptions
+    pub fn available_balance(&self, account_id: AccountId) -> U128 {
+        self.balances
+            .get(&account_id)
+            .unwrap_or_default()
+            .saturating_sub(self.locked_reserve_of(&account_id))
+            .into()
+    }
+
+    /// Basis points of the account's balance committed to the reserves of its outgoing
+    /// subscriptions, 10000 when the reserves take all of it or more
+    pub fn reserve_utilization(&self, account_id: AccountId) -> BasisPoints {
+        let locked_reserve = self.locked_reserve_of(&account_id);
+        let balance = self.balances.get(&account_id).unwrap_or_default();
+        if locked_reserve >= balance {
+            return if locked_reserve == 0 {
+                0
+            } else {
+                MAX_BASIS_POINTS
+            };
+        }
+        (locked_reserve.saturating_mul(MAX_BASIS_POINTS as u128) / balance) as BasisPoints
+    }
+
+    /// Claim settled sNEAR for the caller, moving it into the caller's balance
+    pub fn claim(&mut self) -> U128 {
+        let account_id = env::predecessor_account_id();
+        let amount = self.claimable.remove(&account_id).unwrap_or_default();
+        match self.balances.get(&account_id) {
+            Some(current_balance) => self
+                .balances
+                .insert(&account_id, &current_balance.saturating_add(amount)),
+            None => self.balances.insert(&account_id, &amount),
+        };
+        self.record_holder(&account_id);
+        amount.into()
+    }
+
+    /// Subscriptions for the calling account
+    pub fn subscriptions_by_account(&self) -> Vec<SubscriptionIndex> {
+        self.subscriptions
+            .subscriptions_for_account(env::predecessor_account_id())
+    }
+
+    /// Subscriptions where the calling account is source
+    pub fn my_outgoing_subscriptions(&self) -> Vec<SubscriptionIndex> {
+        self.subscriptions
+            .outputs
+            .get(&env::predecessor_account_id())
+            .unwrap_or_default()
+    }
+
+    /// Subscriptions where the calling account is destination
+    pub fn my_incoming_subscriptions(&self) -> Vec<SubscriptionIndex> {
+        self.subscriptions
+            .inputs
+            .get(&env::predecessor_account_id())
+            .unwrap_or_default()
+    }
+
+    /// Subscriptions of the account which end before the timestamp, open ended subscriptions are
+    /// skipped
+    pub fn expiring_before(
+        &self,
+        account_id: AccountId,
+        before_timestamp: Seconds,
+    ) -> Vec<SubscriptionIndex> {
+        self.subscriptions
+            .subscriptions_for_account(account_id)
+            .into_iter()
+            .filter(|subscription_index| {
+                self.subscription(*subscription_index)
+                    .and_then(|subscription| subscription.end_timestamp)
+                    .is_some_and(|end_timestamp| end_timestamp < before_timestamp)
+            })
+            .collect()
+    }
+
+    /// What the source has accrued to its destinations and not yet settled, beyond what its
+    /// escrows cover, as of now
+    pub fn total_obligation(&self, source: AccountId) -> U128 {
+        self.accrued_outgoing(&source).into()
+    }
+
+    /// The shortfall between what the source has accrued to its destinations and its balance,
+    /// zero when the source is solvent
+    pub fn outstanding_debt(&self, source: AccountId) -> U128 {
+        let balance = self.balances.get(&source).unwrap_or_default();
+        self.accrued_outgoing(&source)
+            .saturating_sub(balance)
+            .into()
+    }
+
+    /// The additional balance the account needs to sustain its current outgoing flow for the
+    /// target duration, zero when its balance is already sufficient
+    pub fn funding_for_runway(&self, account_id: AccountId, target_seconds: Seconds) -> U128 {
+        let outgoing_flow = self
+            .subscriptions
+            .outputs
+            .get(&account_id)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|subscription_index| self.subscription(*subscription_index))
+            .fold(0, |flow: YoctosPerSecond, subscription| {
+                flow.saturating_add(subscription.flow)
+            });
+        outgoing_flow
+            .saturating_mul(target_seconds as u128)
+            .saturating_sub(self.current_balance(account_id).0)
+            .into()
+    }
+
+    /// The current balances of the accounts summed, each account counted as often as it is listed
+    pub fn total_balance_of(&self, accounts: Vec<AccountId>) -> U128 {
+        accounts
+            .into_iter()
+            .try_fold(0, |total: Balance, account_id| {
+                total.checked_add(self.current_balance(account_id).0)
+            })
+            .expect("total balance overflows")
+            .into()
+    }
+
+    /// The current balance of each account in the order listed, at most `MAX_BALANCE_QUERY`
+    pub fn balances_of(&self, accounts: Vec<AccountId>) -> Vec<U128> {
+        require!(accounts.len() <= MAX_BALANCE_QUERY, "too many accounts");
+        accounts
+            .into_iter()
+            .map(|account_id| self.current_balance(account_id))
+            .collect()
+    }
+
+    /// If every sNEAR credited to the contract is backed by wNEAR it holds, so that all of it
+    /// can be unwrapped.  The balance the contract holds is asked of the wrap contract, the
+    /// promise resolves to the answer of `solvency_callback`.
+    pub fn is_solvent(&self) -> Promise {
+        ext_ft::ft_balance_of(
+            env::current_account_id(),
+            self.wrap_contract.clone(),
+            0,
+            5_000_000_000_000u64.into(),
+        )
+        .then(ext_self::solvency_callback(
+            env::current_account_id(),
+            0,
+            5_000_000_000_000u64.into(),
+        ))
+    }
+
+    /// If the wrap contract's token held by the contract, in sNEAR, covers every sNEAR credited
+    #[private]
+    pub fn solvency_callback(&self) -> bool {
+        assert_eq!(env::promise_results_count(), 1, "This is a callback method");
+
+        let held = match env::promise_result(0) {
+            PromiseResult::Successful(result) => {
+                near_sdk::serde_json::from_slice::<U128>(&result).ok()
+            }
+            _ => None,
+        }
+        .expect("balance held on the wrap contract");
+        held.0.saturating_mul(self.wrap_scale()) >= self.streaming_balance
+    }
+
+    /// Destinations the account is streaming to with the total flow to each
+    pub fn outgoing_destinations(&self, account_id: AccountId) -> Vec<(AccountId, U128)> {
+        let mut destinations: Vec<(AccountId, U128)> = vec![];
+        self.subscriptions
+            .outputs
+            .get(&account_id)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|subscription_index| self.subscription(*subscription_index))
+            .for_each(|subscription| {
+                match destinations
+                    .iter_mut()
+                    .find(|(destination, _)| destination == &subscription.destination)
+                {
+                    Some((_, flow)) => flow.0 = flow.0.saturating_add(subscription.flow),
+                    None => destinations.push((subscription.destination, subscription.flow.into())),
+                }
+            });
+        destinations
+    }
+
+    /// Share of the account's total outgoing flow streamed to each destination in basis points.
+    /// Shares are rounded down with the remainder given to the largest so they sum to 10000.
+    pub fn outgoing_flow_distribution(&self, account_id: AccountId) -> Vec<(AccountId, u32)> {
+        let destinations = self.outgoing_destinations(account_id);
+        let total_flow = destinations
+            .iter()
+            .fold(0, |total: YoctosPerSecond, (_, flow)| {
+                total.saturating_add(flow.0)
+            });
+        if total_flow == 0 {
+            return vec![];
+        }
+
+        let mut distribution: Vec<(AccountId, u32)> = destinations
+            .into_iter()
+            .map(|(destination, flow)| {
+                let share = flow.0.saturating_mul(MAX_BASIS_POINTS as u128) / total_flow;
+                (destination, share as u32)
+            })
+            .collect();
+        let allocated = distribution.iter().map(|(_, share)| share).sum::<u32>();
+        if let Some((_, largest)) = distribution.iter_mut().max_by_key(|(_, share)| *share) {
+            *largest += MAX_BASIS_POINTS as u32 - allocated;
+        }
+        distribution
+    }
+
+    /// Estimated bytes of storage used by the account for its balance and subscriptions
+    pub fn storage_usage_of(&self, account_id: AccountId) -> U64 {
+        // A record keyed by the storage prefix and the account or subscription index
+        let account_key_bytes = 1 + 4 + account_id.as_str().len() as u64;
+        let index_key_bytes = 1 + 8;
+
+        let balance_bytes = if self.balances.contains_key(&account_id) {
+            STORAGE_RECORD_BYTES + account_key_bytes + 16
+        } else {
+            0
+        };
+        let subscription_bytes = self
+            .subscriptions
+            .subscriptions_for_account(account_id)
+            .into_iter()
+            .filter_map(|subscription_index| self.subscription(subscription_index))
+            .map(|subscription| {
+                // The subscription and its index in the inputs or outputs of the account
+                STORAGE_RECORD_BYTES
+                    + index_key_bytes
+                    + subscription.try_to_vec().unwrap().len() as u64
+                    + 8
+            })
+            .sum::<u64>();
+        (balance_bytes + subscription_bytes).into()
+    }
+
+    /// Subscriptions of the account in both directions with their counterparties and flows
+    pub fn subscription_graph(&self, account_id: AccountId) -> SubscriptionGraph {
+        let edges = |subscription_indexes: Vec<SubscriptionIndex>, incoming: bool| {
+            subscription_indexes
+                .into_iter()
+                .filter_map(|subscription_index| {
+                    self.subscription(subscription_index).map(|subscription| {
+                        let counterparty = if incoming {
+                            subscription.source
+                        } else {
+                            subscription.destination
+                        };
+                        (subscription_index, counterparty, subscription.flow.into())
+                    })
+                })
+                .collect()
+        };
+        SubscriptionGraph {
+            incoming: edges(
+                self.subscriptions
+                    .inputs
+                    .get(&account_id)
+                    .unwrap_or_default(),
+                true,
+            ),
+            outgoing: edges(
+                self.subscriptions
+                    .outputs
+                    .get(&account_id)
+                    .unwrap_or_default(),
+                false,
+            ),
+        }
+    }
+
+    /// Total incoming flow of the account over a year of 365 days
+    pub fn annualized_incoming(&self, account_id: AccountId) -> U128 {
+        self.subscriptions
+            .inputs
+            .get(&account_id)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|subscription_index| self.subscription(*subscription_index))
+            .try_fold(0, |flow: YoctosPerSecond, subscription| {
+                flow.checked_add(subscription.flow)
+            })
+            .and_then(|flow| flow.checked_mul(SECONDS_PER_YEAR as u128))
+            .expect("amount overflows")
+            .into()
+    }
+
+    /// The amount accrued by the subscription waiting to be settled
+    pub fn pending_settlement(&self, subscription_index: SubscriptionIndex) -> U128 {
+        self.try_subscription(subscription_index)
+            .unwrap()
+            .accrued(self.accrual_timestamp())
+            .into()
+    }
+
+    /// Up to `limit` of the account's incoming subscriptions with the amount pending settlement,
+    /// largest pending first
+    pub fn incoming_by_pending(
+        &self,
+        account_id: AccountId,
+        limit: u64,
+    ) -> Vec<(SubscriptionIndex, U128)> {
+        let timestamp = self.accrual_timestamp();
+        let mut pending: Vec<(SubscriptionIndex, U128)> = self
+            .subscriptions
+            .inputs
+            .get(&account_id)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|subscription_index| {
+                self.subscription(subscription_index).map(|subscription| {
+                    (subscription_index, subscription.accrued(timestamp).into())
+                })
+            })
+            .collect();
+        pending.sort_by_key(|(_, amount)| std::cmp::Reverse(amount.0));
+        pending.truncate(limit as usize);
+        pending
+    }
+
+    /// Subscriptions streaming to the destination with a flow at or above the minimum
+    pub fn incoming_above(
+        &self,
+        destination: AccountId,
+        min_flow: YoctosPerSecond,
+    ) -> Vec<SubscriptionIndex> {
+        self.subscriptions
+            .inputs
+            .get(&destination)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|subscription_index| {
+                self.subscription(*subscription_index)
+                    .is_some_and(|subscription| subscription.flow >= min_flow)
+            })
+            .collect()
+    }
+
+    /// The role of the account in a subscription, either source or destination
+    pub fn role_in_subscription(
+        &self,
+        subscription_index: SubscriptionIndex,
+        account_id: AccountId,
+    ) -> Option<String> {
+        self.subscription(subscription_index)
+            .and_then(|subscription| subscription.role(&account_id))
+            .map(String::from)
+    }
+
+    /// Seconds since the subscription was created
+    pub fn subscription_age(&self, subscription_index: SubscriptionIndex) -> U64 {
+        let subscription = self.try_subscription(subscription_index).unwrap();
+        env::block_timestamp()
+            .saturating_sub(subscription.created_at)
+            .into()
+    }
+
+    /// Metadata of the token the subscription streams, subscriptions stream sNEAR
+    pub fn subscription_token_metadata(
+        &self,
+        subscription_index: SubscriptionIndex,
+    ) -> FungibleTokenMetadata {
+        self.try_subscription(subscription_index).unwrap();
+        self.token_metadata
+            .get(&env::current_account_id())
+            .expect("token not registered")
+    }
+
+    /// The index the next subscription created will be assigned.  Indexes are never reused, once
+    /// `u64::MAX` has been assigned no more subscriptions can be created.
+    pub fn peek_next_index(&self) -> U64 {
+        self.subscriptions
+            .subscription_index
+            .checked_add(1)
+            .expect("index space exhausted")
+            .into()
+    }
+
+    /// A subscription by index
+    pub fn get_subscription(&self, subscription_index: SubscriptionIndex) -> Subscription {
+        self.try_subscription(subscription_index).unwrap()
+    }
+
+    /// A subscription by index, or the error if it isn't present
+    pub fn try_get_subscription(
+        &self,
+        subscription_index: SubscriptionIndex,
+    ) -> SubscriptionResult {
+        self.try_subscription(subscription_index)
+    }
+
+    /// The average flow of the subscription between two times, weighting each flow it has had by
+    /// the time it was in effect.  Nothing flows before creation or after the end.
+    pub fn average_flow(
+        &self,
+        subscription_index: SubscriptionIndex,
+        from: Seconds,
+        to: Seconds,
+    ) -> U128 {
+        require!(from < to, "window must not be empty");
+        let subscription = self.try_subscription(subscription_index).unwrap();
+        let flow_history = self
+            .flow_history
+            .get(&subscription_index)
+            .unwrap_or_else(|| vec![(subscription.created_at, subscription.flow)]);
+        let end = subscription
+            .end_timestamp
+            .map_or(to, |end_timestamp| to.min(end_timestamp));
+
+        let mut streamed: Balance = 0;
+        for (i, (changed_at, flow)) in flow_history.iter().enumerate() {
+            let until = flow_history
+                .get(i + 1)
+                .map_or(end, |(next_changed_at, _)| end.min(*next_changed_at));
+            let elapsed = until.saturating_sub(from.max(*changed_at));
+            streamed = streamed.saturating_add((elapsed as u128).saturating_mul(*flow));
+        }
+        (streamed / (to - from) as u128).into()
+    }
+
+    /// Total the source has paid to the subscription over its life
+    pub fn lifetime_paid(&self, subscription_index: SubscriptionIndex) -> U128 {
+        self.try_subscription(subscription_index)
+            .unwrap()
+            .total_settled
+            .into()
+    }
+
+    /// The time a capped subscription will complete, `None` for a subscription without a cap or
+    /// with a zero flow
+    pub fn completion_time(&self, subscription_index: SubscriptionIndex) -> Option<U64> {
+        self.try_subscription(subscription_index)
+            .unwrap()
+            .completion_time()
+            .map(U64)
+    }
+
+    /// Subscriptions by index in the order requested, `None` for those which don't exist
+    pub fn get_subscriptions(&self, indices: Vec<SubscriptionIndex>) -> Vec<Option<Subscription>> {
+        indices
+            .into_iter()
+            .map(|subscription_index| self.subscription(subscription_index))
+            .collect()
+    }
+
+    /// Update the flow of the subscription, called by the source.  Changing the flow will force
+    /// the stream to be settled at this point in time and from then the new flow will take
+    /// effect.  Raising the flow requires the reserve for the new flow.
+    pub fn update_subscription(
+        &mut self,
+        subscription_index: SubscriptionIndex,
+        new_flow: YoctosPerSecond,
+    ) -> Subscription {
+        self.internal_update_subscription(
+            &env::predecessor_account_id(),
+            subscription_index,
+            new_flow,
+        )
+    }
+
+    /// Update the flow of the subscription as `update_subscription` does, returning the error
+    /// rather than panicking if the subscription isn't present or already has the flow
+    pub fn try_update_subscription(
+        &mut self,
+        subscription_index: SubscriptionIndex,
+        new_flow: YoctosPerSecond,
+    ) -> SubscriptionResult {
+        if self.try_subscription(subscription_index)?.flow == new_flow {
+            return Err(SubscriptionError::InvalidFlow(new_flow));
+        }
+        Ok(self.update_subscription(subscription_index, new_flow))
+    }
+}
+
+impl Paystream {
+    /// Update the flow of the subscription for the caller, who must be its source
+    fn internal_update_subscription(
+        &mut self,
+        caller: &AccountId,
+        subscription_index: SubscriptionIndex,
+        new_flow: YoctosPerSecond,
+    ) -> Subscription {
+        require!(new_flow >= self.min_flow, "flow below minimum");
+        let mut subscription = self.try_subscription(subscription_index).unwrap();
+        require!(&subscription.source == caller, "caller must be source");
+        if new_flow > subscription.flow {
+            self.sufficient_reserve(new_flow, &subscription.source, subscription.locked_reserve);
+        }
+        require!(
+            subscription.is_active(env::block_timestamp()),
+            "subscription not active"
+        );
+        require!(
+            !self.escrows.contains_key(&subscription_index),
+            "subscription is escrowed"
+        );
+        self.require_within_incoming_cap(&subscription.destination, subscription.flow, new_flow);
+        self.untrack(subscription_index, &subscription);
+        let amount = subscription.settle(self.accrual_timestamp());
+        self.draw_escrow(subscription_index, &subscription.source, amount);
+        self.try_transfer(
+            subscription.source.clone(),
+            subscription.destination.clone(),
+            amount,
+            subscription.pull,
+        )
+        .unwrap();
+        subscription.total_settled = subscription.total_settled.saturating_add(amount);
+        self.distribute_settlement(subscription_index, &subscription, amount);
+        self.committed_flow = self
+            .committed_flow
+            .saturating_sub(subscription.flow)
+            .saturating_add(new_flow);
+        self.subscriptions
+            .try_replace(subscription_index, subscription)
+            .unwrap();
+        let subscription = self
+            .subscriptions
+            .try_update(subscription_index, new_flow)
+            .unwrap();
+        self.track(subscription_index, &subscription);
+        let mut flow_history = self
+            .flow_history
+            .get(&subscription_index)
+            .unwrap_or_default();
+        flow_history.push((env::block_timestamp(), new_flow));
+        self.flow_history.insert(&subscription_index, &flow_history);
+        subscription
+    }
+
+    /// Credit the source with the sNEAR for the wNEAR it has transferred to the contract and
+    /// update the flow of its subscription in one call.  The reserve for the new flow is checked
+    /// once the top up has been credited.
+    fn update_subscription_with_topup(
+        &mut self,
+        source: AccountId,
+        subscription_index: SubscriptionIndex,
+        new_flow: YoctosPerSecond,
+        topup: Balance,
+    ) -> Subscription {
+        self.credit_deposit(&source, topup);
+        self.internal_update_subscription(&source, subscription_index, new_flow)
+    }
+}
+
+#[near_bindgen]
+impl Paystream {
+    /// Create the paystream contract with the wrapped token contract wNEAR
+    #[init]
+    pub fn new(owner: AccountId, wrap_contract: AccountId) -> Self {
+        require!(!env::state_exists(), "Already initialized");
+        // Metadata for the wrapped wrapper
+        let metadata = FungibleTokenMetadata {
+            spec: FT_METADATA_SPEC.into(),
+            name: STREAM_NAME.into(),
+            symbol: STREAM_SYMBOL.into(),
+            icon: Some(DATA_IMAGE_SVG_NEAR_ICON.into()),
+            reference: None,
+            reference_hash: None,
+            decimals: DECIMALS,
+        };
+        metadata.assert_valid();
+
+        // Initialise contract
+        let mut this = Self {
+            wrap_contract,
+            balances: LookupMap::new(StorageKey::Balances),
+            claimable: LookupMap::new(StorageKey::Claimable),
+            token: FungibleToken::new(StorageKey::FungibleToken),
+            metadata: LazyOption::new(StorageKey::Metadata, Some(&metadata)),
+            owner: owner.clone(),
+            treasurer: owner.clone(),
+            subscriptions: Subscriptions {
+                subscription_index: 0,
+                subscriptions: LookupMap::new(StorageKey::Subscriptions),
+                outputs: LookupMap::new(StorageKey::Outputs),
+                inputs: LookupMap::new(StorageKey::Inputs),
+            },
+            reserve: 4 * 60 * 60, // 4 hours
+            min_flow: 0,
+            last_create: LookupMap::new(StorageKey::LastCreate),
+            create_cooldown: 0,
+            frozen: LookupSet::new(StorageKey::Frozen),
+            templates: LookupMap::new(StorageKey::Templates),
+            fee_basis_points: 0,
+            max_subscriptions_per_account: u32::MAX,
+            active_subscriptions: 0,
+            committed_flow: 0,
+            streaming_balance: 0,
+            frozen_accounts: 0,
+            end_time_approvals: LookupMap::new(StorageKey::EndTimeApprovals),
+            account_flows: LookupMap::new(StorageKey::AccountFlows),
+            frozen_at: None,
+            frozen_offset: 0,
+            wrap_decimals: None,
+            token_metadata: LookupMap::new(StorageKey::TokenMetadata),
+            fees_collected: 0,
+            allowed_destinations: LookupSet::new(StorageKey::AllowedDestinations),
+            enforce_allowlist: LookupSet::new(StorageKey::EnforceAllowlist),
+            max_incoming_flow: LookupMap::new(StorageKey::MaxIncomingFlow),
+            settlement_nonces: LookupMap::new(StorageKey::SettlementNonces),
+            flow_history: LookupMap::new(StorageKey::FlowHistory),
+            request_index: 0,
+            requests: LookupMap::new(StorageKey::Requests),
+            source_requests: LookupMap::new(StorageKey::SourceRequests),
+            deployed_at: env::block_timestamp(),
+            reserve_tiers: vec![],
+            acknowledgment_required: LookupSet::new(StorageKey::AcknowledgmentRequired),
+            relay_keys: LookupMap::new(StorageKey::RelayKeys),
+            relay_nonces: LookupMap::new(StorageKey::RelayNonces),
+            rounding_mode: RoundingMode::Floor,
+            fees_by_day: LookupMap::new(StorageKey::FeesByDay),
+            shutdown_phase: ShutdownPhase::Running,
+            shutdown_cursor: 1,
+            refund_cursor: 0,
+            holders: Vector::new(StorageKey::Holders),
+            holder_set: LookupSet::new(StorageKey::HolderSet),
+            notification_targets: LookupMap::new(StorageKey::NotificationTargets),
+            escrows: LookupMap::new(StorageKey::Escrows),
+            passthroughs: LookupMap::new(StorageKey::Passthroughs),
+            group_index: 0,
+            groups: LookupMap::new(StorageKey::Groups),
+            group_owners: LookupMap::new(StorageKey::GroupOwners),
+            incoming_splits: LookupMap::new(StorageKey::IncomingSplits),
+            clawback_seconds: DEFAULT_CLAWBACK_SECONDS,
+            settlement_credits: LookupMap::new(StorageKey::SettlementCredits),
+            locked_reserves: LookupMap::new(StorageKey::LockedReserves),
+        };
+
+        this.token_metadata
+            .insert(&env::current_account_id(), &metadata);
+        this.token.internal_register_account(&owner);
+        // No initial supply
+        this.token.internal_deposit(&owner, 0);
+        this
+    }
+
+    /// Migrate the state stored by version 0.1.0 of the contract to the current layout, called
+    /// once after deploying new code.  When the layout of `Paystream` or `Subscription` changes
+    /// the previous layout is kept as a versioned struct and migrated from here.
+    #[init(ignore_state)]
+    #[private]
+    pub fn migrate() -> Self {
+        let old: PaystreamV1 = env::state_read().expect("state to migrate");
+        let mut subscriptions = old.subscriptions;
+        let mut active_subscriptions = 0;
+        let mut committed_flow: YoctosPerSecond = 0;
+
+        let mut migrated = vec![];
+
+        let mut legacy_subscriptions: LookupMap<SubscriptionIndex, SubscriptionV1> =
+            LookupMap::new(StorageKey::Subscriptions);
+        for subscription_index in 1..=subscriptions.subscription_index {
+            if let Some(subscription) = legacy_subscriptions.remove(&subscription_index) {
+                active_subscriptions += 1;
+                committed_flow = committed_flow.saturating_add(subscription.flow);
+                let subscription = Subscription {
+                    source: subscription.source,
+                    destination: subscription.destination,
+                    flow: subscription.flow,
+                    timestamp: subscription.timestamp,
+                    end_timestamp: None,
+                    paid_from: subscription.timestamp,
+                    created_at: subscription.timestamp,
+                    lump_sum: 0,
+                    unlock_at: 0,
+                    locked_reserve: subscription.flow.saturating_mul(old.reserve as u128),
+                    pull: true,
+                    total_settled: 0,
+                    active: true,
+                    cap: None,
+                    condition: None,
+                    scaled_flow: None,
+                    scaled_remainder: 0,
+                    notify: None,
+                    group_id: None,
+                    frozen_offset: 0,
+                };
+                subscriptions
+                    .subscriptions
+                    .insert(&subscription_index, &subscription);
+                migrated.push((subscription_index, subscription));
+            }
+        }
+
+        let mut this = Self {
+            wrap_contract: old.wrap_contract,
+            token: old.token,
+            metadata: old.metadata,
+            balances: old.balances,
+            claimable: LookupMap::new(StorageKey::Claimable),
+            owner: old.owner,
+            treasurer: old.treasurer,
+            subscriptions,
+            reserve: old.reserve,
+            min_flow: 0,
+            last_create: LookupMap::new(StorageKey::LastCreate),
+            create_cooldown: 0,
+            frozen: LookupSet::new(StorageKey::Frozen),
+            templates: LookupMap::new(StorageKey::Templates),
+            fee_basis_points: 0,
+            max_subscriptions_per_account: u32::MAX,
+            active_subscriptions,
+            committed_flow,
+            // Balances can't be enumerated so the total is counted from deposits after migration
+            streaming_balance: 0,
+            frozen_accounts: 0,
+            end_time_approvals: LookupMap::new(StorageKey::EndTimeApprovals),
+            account_flows: LookupMap::new(StorageKey::AccountFlows),
+            frozen_at: None,
+            frozen_offset: 0,
+            wrap_decimals: None,
+            token_metadata: LookupMap::new(StorageKey::TokenMetadata),
+            fees_collected: 0,
+            allowed_destinations: LookupSet::new(StorageKey::AllowedDestinations),
+            enforce_allowlist: LookupSet::new(StorageKey::EnforceAllowlist),
+            max_incoming_flow: LookupMap::new(StorageKey::MaxIncomingFlow),
+            settlement_nonces: LookupMap::new(StorageKey::SettlementNonces),
+            flow_history: LookupMap::new(StorageKey::FlowHistory),
+            request_index: 0,
+            requests: LookupMap::new(StorageKey::Requests),
+            source_requests: LookupMap::new(StorageKey::SourceRequests),
+            // The time of the original deployment wasn't stored
+            deployed_at: env::block_timestamp(),
+            reserve_tiers: vec![],
+            acknowledgment_required: LookupSet::new(StorageKey::AcknowledgmentRequired),
+            relay_keys: LookupMap::new(StorageKey::RelayKeys),
+            relay_nonces: LookupMap::new(StorageKey::RelayNonces),
+            rounding_mode: RoundingMode::Floor,
+            fees_by_day: LookupMap::new(StorageKey::FeesByDay),
+            shutdown_phase: ShutdownPhase::Running,
+            shutdown_cursor: 1,
+            refund_cursor: 0,
+            holders: Vector::new(StorageKey::Holders),
+            holder_set: LookupSet::new(StorageKey::HolderSet),
+            notification_targets: LookupMap::new(StorageKey::NotificationTargets),
+            escrows: LookupMap::new(StorageKey::Escrows),
+            passthroughs: LookupMap::new(StorageKey::Passthroughs),
+            group_index: 0,
+            groups: LookupMap::new(StorageKey::Groups),
+            group_owners: LookupMap::new(StorageKey::GroupOwners),
+            incoming_splits: LookupMap::new(StorageKey::IncomingSplits),
+            clawback_seconds: DEFAULT_CLAWBACK_SECONDS,
+            settlement_credits: LookupMap::new(StorageKey::SettlementCredits),
+            locked_reserves: LookupMap::new(StorageKey::LockedReserves),
+        };
+
+        for (subscription_index, subscription) in migrated {
+            this.track(subscription_index, &subscription);
+            this.lock_reserve(&subscription.source, subscription.locked_reserve);
+        }
+        if let Some(metadata) = this.metadata.get() {
+            this.token_metadata
+                .insert(&env::current_account_id(), &metadata);
+        }
+        this
+    }
+
+    /// Statistics across the contract
+    pub fn stats(&self) -> ContractStats {
+        ContractStats {
+            active_subscriptions: self.active_subscriptions,
+            committed_flow: self.committed_flow.into(),
+            streaming_balance: self.streaming_balance.into(),
+            frozen_accounts: self.frozen_accounts,
+            timestamp: env::block_timestamp(),
+        }
+    }
+
+    /// Number of subscriptions ever created, including those since removed
+    pub fn total_created(&self) -> U64 {
+        self.subscriptions.subscription_index.into()
+    }
+
+    /// Number of subscriptions which have not been removed
+    pub fn total_active(&self) -> U64 {
+        self.active_subscriptions.into()
+    }
+
+    /// Version of the contract
+    pub fn version(&self) -> String {
+        env!("CARGO_PKG_VERSION").into()
+    }
+
+    /// Capabilities of this version of the contract, for clients to check before relying on them
+    pub fn supported_features(&self) -> Vec<String> {
+        SUPPORTED_FEATURES
+            .iter()
+            .map(|feature| feature.to_string())
+            .collect()
+    }
+
+    /// The time the contract was initialised
+    pub fn deployed_at(&self) -> U64 {
+        self.deployed_at.into()
+    }
+
+    /// Wrap NEAR as wNEAR as a cross contract call and on success credit the
+    /// account's balance as sNEAR.  On first use the wrap contract is verified to be a fungible
+    /// token before the NEAR is wrapped, this verifies a wrap contract with fewer decimals than
+    /// sNEAR without wrapping so its token can be transferred in with `ft_transfer_call`.
+    #[payable]
+    pub fn wrap_near(&mut self) -> Promise {
+        require!(
+            self.shutdown_phase == ShutdownPhase::Running,
+            "contract is shutting down"
+        );
+        let account_id = env::predecessor_account_id();
+        let amount = env::attached_deposit();
+        if self.wrap_decimals.is_some() {
+            require!(
+                self.wraps_near(),
+                "wrap contract doesn't wrap NEAR, transfer its token with ft_transfer_call"
+            );
+            return self.deposit_to_wrap_contract(account_id, amount);
+        }
+
+        ext_ft::ft_metadata(self.wrap_contract.clone(), 0, 5_000_000_000_000u64.into()).then(
+            ext_self::verify_wrap_callback(
+                account_id,
+                amount,
+                env::current_account_id(),
+                0,
+                20_000_000_000_000u64.into(),
+            ),
+        )
+    }
+
+    /// The wrap contract NEAR is wrapped with
+    pub fn wrap_contract(&self) -> &AccountId {
+        &self.wrap_contract
+    }
+
+    /// Decimals of the wrap contract, `None` until it has been verified
+    pub fn wrap_decimals(&self) -> Option<u8> {
+        self.wrap_decimals
+    }
+
+    /// Decimals of a token registered with the contract, sNEAR by the id of this contract
+    pub fn token_decimals(&self, token: AccountId) -> u8 {
+        self.token_metadata
+            .get(&token)
+            .expect("token not registered")
+            .decimals
+    }
+
+    /// Store the decimals of the wrap contract if it responded with its metadata and go on to
+    /// wrap the NEAR, otherwise refund the deposit attached to `wrap_near`.  A token with fewer
+    /// decimals than sNEAR is scaled up to sNEAR and doesn't wrap NEAR, so the deposit is
+    /// refunded once its decimals are stored.  The wrap contract is left unverified if it has
+    /// more decimals than sNEAR.
+    #[private]
+    pub fn verify_wrap_callback(&mut self, account_id: AccountId, amount: Balance) -> Promise {
+        assert_eq!(env::promise_results_count(), 1, "This is a callback method");
+
+        let metadata = match env::promise_result(0) {
+            PromiseResult::Successful(result) => {
+                near_sdk::serde_json::from_slice::<FungibleTokenMetadata>(&result).ok()
+            }
+            _ => None,
+        };
+        match metadata {
+            Some(metadata) if metadata.decimals > DECIMALS => {
+                self.token_metadata.insert(&self.wrap_contract, &metadata);
+                log!(
+                    "wrap contract has {} decimals, more than {}, refunding @{} with {}",
+                    metadata.decimals,
+                    DECIMALS,
+                    account_id,
+                    amount
+                );
+                Promise::new(account_id).transfer(amount)
+            }
+            Some(metadata) if metadata.decimals < DECIMALS => {
+                self.wrap_decimals = Some(metadata.decimals);
+                self.token_metadata.insert(&self.wrap_contract, &metadata);
+                log!(
+                    "wrap contract has {} decimals so doesn't wrap NEAR, refunding @{} with {}",
+                    metadata.decimals,
+                    account_id,
+                    amount
+                );
+                Promise::new(account_id).transfer(amount)
+            }
+            Some(metadata) => {
+                self.wrap_decimals = Some(metadata.decimals);
+                self.token_metadata.insert(&self.wrap_contract, &metadata);
+                self.deposit_to_wrap_contract(account_id, amount)
+            }
+            None => {
+                log!(
+                    "wrap contract is not a fungible token, refunding @{} with {}",
+                    account_id,
+                    amount
+                );
+                Promise::new(account_id).transfer(amount)
+            }
+        }
+    }
+
+    /// Unwrap wNEAR and credit the caller the amount in NEAR.  The amount is debited from the
+    /// caller's balance of sNEAR before wNEAR is withdrawn and restored if the withdrawal fails.
+    /// When the wrap contract's token has fewer decimals than sNEAR the amount must be whole units
+    /// of the token, which is transferred to the caller.
+    #[payable]
+    pub fn unwrap_near(&mut self, amount: Balance) -> Promise {
+        require!(
+            amount.is_multiple_of(self.wrap_scale()),
+            "amount must be whole units of the wrap contract's token"
+        );
+        let account_id = env::predecessor_account_id();
+        let balance = self.balances.get(&account_id).unwrap_or_default();
+        require!(balance >= amount, "insufficient balance");
+        require!(
+            balance.saturating_sub(self.locked_reserve_of(&account_id)) >= amount,
+            "balance is locked by subscription reserves"
+        );
+        self.balances.insert(&account_id, &(balance - amount));
+        self.streaming_balance = self.streaming_balance.saturating_sub(amount);
+        self.withdraw_to_account(account_id, amount)
+    }
+
+    /// Send the unwrapped NEAR to the account, or restore its balance of sNEAR if wNEAR couldn't
+    /// be withdrawn
+    #[private]
+    pub fn unwrap_callback(&mut self, account_id: AccountId, amount: Balance) {
+        assert_eq!(env::promise_results_count(), 1, "This is a callback method");
+
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            // wNEAR failed to be withdrawn, restore the balance debited by `unwrap_near`
+            PromiseResult::Failed => {
+                log!("failed callback, restoring @{} with {}", account_id, amount);
+                match self.balances.get(&account_id) {
+                    Some(current_balance) => self
+                        .balances
+                        .insert(&account_id, &current_balance.saturating_add(amount)),
+                    None => self.balances.insert(&account_id, &amount),
+                };
+                self.streaming_balance = self.streaming_balance.saturating_add(amount);
+                // A refund failed, so there is a balance to refund again and the holders are
+                // checked again from the start
+                if self.shutdown_phase == ShutdownPhase::Closed {
+                    self.shutdown_phase = ShutdownPhase::Refunding;
+                }
+                if self.shutdown_phase == ShutdownPhase::Refunding {
+                    self.refund_cursor = 0;
+                }
+            }
+            // wNEAR has been withdrawn, send the NEAR to the account.  A token with fewer
+            // decimals than sNEAR has already been transferred to it.
+            PromiseResult::Successful(_) => {
+                if self.wraps_near() {
+                    Promise::new(account_id).transfer(amount);
+                }
+            }
+        }
+    }
+
+    #[private]
+    pub fn wrap_callback(&mut self, account_id: AccountId, amount: Balance) {
+        // Checked here as well as by `#[private]` so the refund and credit can't be driven by
+        // arguments from any other caller, whatever the build
+        Self::required(&env::current_account_id());
+        assert_eq!(env::promise_results_count(), 1, "This is a callback method");
+
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            // Near failed to be wrapped, refund the deposit attached to `wrap_near`
+            PromiseResult::Failed => {
+                log!("failed callback, refunding @{} with {}", account_id, amount);
+                Promise::new(account_id).transfer(amount);
+            }
+            // Near has been wrapped, update balance of sNEAR for account
+            PromiseResult::Successful(_) => self.credit_deposit(&account_id, amount),
+        }
+    }
+}
+
+impl Paystream {
+    /// Credit the account with the sNEAR for wNEAR deposited with the contract
+    fn credit_deposit(&mut self, account_id: &AccountId, amount: Balance) {
+        match self.balances.get(account_id) {
+            Some(current_balance) => self
+                .balances
+                .insert(account_id, &current_balance.saturating_add(amount)),
+            None => self.balances.insert(account_id, &amount),
+        };
+        self.record_holder(account_id);
+        self.streaming_balance = self.streaming_balance.saturating_add(amount);
+    }
+
+    /// sNEAR for each unit of the wrap contract's token, one unless the token has fewer decimals
+    /// than sNEAR.  An unverified wrap contract is taken to have the decimals of sNEAR.
+    fn wrap_scale(&self) -> Balance {
+        let decimals = self.wrap_decimals.unwrap_or(DECIMALS);
+        10u128.pow(u32::from(DECIMALS - decimals))
+    }
+
+    /// If the wrap contract wraps NEAR, rather than being a token with fewer decimals than sNEAR
+    fn wraps_near(&self) -> bool {
+        self.wrap_scale() == 1
+    }
+
+    /// Withdraw the amount of wNEAR, already debited from the account, and send it to the account
+    /// in NEAR.  When the wrap contract's token has fewer decimals than sNEAR the whole units of
+    /// its token are transferred instead.
+    fn withdraw_to_account(&self, account_id: AccountId, amount: Balance) -> Promise {
+        let withdrawal = if self.wraps_near() {
+            ext_wnear::near_withdraw(
+                amount.into(),
+                self.wrap_contract.clone(),
+                1,
+                5_000_000_000_000u64.into(),
+            )
+        } else {
+            ext_ft::ft_transfer(
+                account_id.clone(),
+                (amount / self.wrap_scale()).into(),
+                None,
+                self.wrap_contract.clone(),
+                1,
+                5_000_000_000_000u64.into(),
+            )
+        };
+        withdrawal.then(ext_self::unwrap_callback(
+            account_id,
+            amount,
+            env::current_account_id(),
+            0,
+            5_000_000_000_000u64.into(),
+        ))
+    }
+
+    /// Call `on_settlement` on the subscription's `notify`, or else the destination's notification
+    /// target, when an amount has been settled.  The call isn't waited on.
+    fn notify_settlement(
+        &self,
+        subscription_index: SubscriptionIndex,
+        subscription: &Subscription,
+        amount: Balance,
+    ) {
+        if amount == 0 {
+            return;
+        }
+        let target = subscription
+            .notify
+            .clone()
+            .or_else(|| self.notification_targets.get(&subscription.destination));
+        if let Some(target) = target {
+            ext_notify::on_settlement(
+                subscription_index,
+                amount.into(),
+                target,
+                0,
+                5_000_000_000_000u64.into(),
+            );
+        }
+    }
+
+    /// Take a removed subscription out of its group, if it is in one
+    fn ungroup(&mut self, subscription_index: SubscriptionIndex, subscription: &Subscription) {
+        if let Some(group_id) = subscription.group_id {
+            if let Some(mut members) = self.groups.get(&group_id) {
+                members.retain(|member| *member != subscription_index);
+                self.groups.insert(&group_id, &members);
+            }
+        }
+    }
+
+    /// Reserves locked by the outgoing subscriptions of the account
+    fn locked_reserve_of(&self, account_id: &AccountId) -> Balance {
+        self.locked_reserves.get(account_id).unwrap_or_default()
+    }
+
+    /// Add the reserve of a new subscription to the total locked by its source
+    fn lock_reserve(&mut self, source: &AccountId, locked_reserve: Balance) {
+        if locked_reserve == 0 {
+            return;
+        }
+        let locked = self
+            .locked_reserve_of(source)
+            .saturating_add(locked_reserve);
+        self.locked_reserves.insert(source, &locked);
+    }
+
+    /// Take the reserve of a removed subscription from the total locked by its source
+    fn release_reserve(&mut self, source: &AccountId, locked_reserve: Balance) {
+        let locked = self
+            .locked_reserve_of(source)
+            .saturating_sub(locked_reserve);
+        if locked == 0 {
+            self.locked_reserves.remove(source);
+        } else {
+            self.locked_reserves.insert(source, &locked);
+        }
+    }
+
+    /// Pass on what the destination received from settling the amount, after the fee: first the
+    /// passthrough share of the subscription onward, then the rest between the payees of the
+    /// destination's incoming split by weight.  Shares are rounded by the rounding mode, what
+    /// rounding leaves over stays with the destination.
+    fn distribute_settlement(
+        &mut self,
+        subscription_index: SubscriptionIndex,
+        subscription: &Subscription,
+        amount: Balance,
+    ) {
+        let mut received = amount - self.fee(amount);
+        let mut kept = received;
+        if let Some((onward_destination, fraction_bps)) = self.passthroughs.get(&subscription_index)
+        {
+            let share = self
+                .rounding_mode
+                .divide(
+                    received.saturating_mul(fraction_bps as u128),
+                    MAX_BASIS_POINTS as u128,
+                )
+                .min(received);
+            if self.move_credit(
+                &subscription.destination,
+                &onward_destination,
+                share,
+                subscription.pull,
+            ) {
+                self.record_credit(
+                    subscription_index,
+                    &onward_destination,
+                    subscription.pull,
+                    share,
+                );
+                kept -= share;
+            }
+            received -= share;
+        }
+        let splits = self
+            .incoming_splits
+            .get(&subscription.destination)
+            .unwrap_or_default();
+        let total_weight: u128 = splits.iter().map(|(_, weight)| *weight as u128).sum();
+        let mut undivided = received;
+        for (payee, weight) in splits.iter() {
+            // Rounding up never pays the payees more than was received between them
+            let share = self
+                .rounding_mode
+                .divide(received.saturating_mul(*weight as u128), total_weight)
+                .min(undivided);
+            if self.move_credit(&subscription.destination, payee, share, subscription.pull) {
+                self.record_credit(subscription_index, payee, subscription.pull, share);
+                undivided -= share;
+                kept -= share;
+            }
+        }
+        self.record_credit(
+            subscription_index,
+            &subscription.destination,
+            subscription.pull,
+            kept,
+        );
+    }
+
+    /// Add to the net amount the settlements of the subscription have credited to the account
+    fn record_credit(
+        &mut self,
+        subscription_index: SubscriptionIndex,
+        account_id: &AccountId,
+        pull: bool,
+        amount: Balance,
+    ) {
+        if amount == 0 {
+            return;
+        }
+        let mut credits = self
+            .settlement_credits
+            .get(&subscription_index)
+            .unwrap_or_default();
+        match credits
+            .iter_mut()
+            .find(|(credited, credited_pull, _)| credited == account_id && *credited_pull == pull)
+        {
+            Some((_, _, total)) => *total = total.saturating_add(amount),
+            None => credits.push((account_id.clone(), pull, amount)),
+        }
+        self.settlement_credits
+            .insert(&subscription_index, &credits);
+    }
+
+    /// Drop what is kept for a subscription once it has been removed, returning what remains in
+    /// its escrow to the source
+    fn forget_subscription(
+        &mut self,
+        subscription_index: SubscriptionIndex,
+        subscription: &Subscription,
+    ) {
+        self.refund_escrow(subscription_index, &subscription.source);
+        self.passthroughs.remove(&subscription_index);
+        self.settlement_credits.remove(&subscription_index);
+        self.flow_history.remove(&subscription_index);
+        self.settlement_nonces.remove(&subscription_index);
+        self.end_time_approvals.remove(&subscription_index);
+        self.release_reserve(&subscription.source, subscription.locked_reserve);
+        self.ungroup(subscription_index, subscription);
+    }
+
+    /// Move an amount credited by settlement from one account to another, between claimable
+    /// balances when pulled and balances otherwise.  Returns if anything moved.
+    fn move_credit(
+        &mut self,
+        from: &AccountId,
+        to: &AccountId,
+        amount: Balance,
+        pull: bool,
+    ) -> bool {
+        if amount == 0 || from == to {
+            return false;
+        }
+        let credited = if pull {
+            &mut self.claimable
+        } else {
+            &mut self.balances
+        };
+        let held = credited.get(from).unwrap_or_default();
+        credited.insert(from, &held.saturating_sub(amount));
+        let balance = credited.get(to).unwrap_or_default();
+        credited.insert(to, &balance.saturating_add(amount));
+        self.record_holder(to);
+        true
+    }
+
+    /// Move up to the amount from the escrow of the subscription, if it is escrowed, into the
+    /// balance of its source ready to be settled
+    fn draw_escrow(
+        &mut self,
+        subscription_index: SubscriptionIndex,
+        source: &AccountId,
+        amount: Balance,
+    ) {
+        if let Some(escrow) = self.escrows.get(&subscription_index) {
+            let drawn = amount.min(escrow);
+            self.escrows.insert(&subscription_index, &(escrow - drawn));
+            let balance = self.balances.get(source).unwrap_or_default();
+            self.balances.insert(source, &balance.saturating_add(drawn));
+        }
+    }
+
+    /// Return what remains in the escrow of a removed subscription to its source
+    fn refund_escrow(&mut self, subscription_index: SubscriptionIndex, source: &AccountId) {
+        if let Some(escrow) = self.escrows.remove(&subscription_index) {
+            let balance = self.balances.get(source).unwrap_or_default();
+            self.balances
+                .insert(source, &balance.saturating_add(escrow));
+        }
+    }
+
+    /// Record the account as a holder of a balance if it isn't already
+    fn record_holder(&mut self, account_id: &AccountId) {
+        if self.holder_set.insert(account_id) {
+            self.holders.push(account_id);
+        }
+    }
+
+    /// Warn destinations with an `insolvency_warning` event when a source couldn't settle all it
+    /// has accrued
+    fn warn_insolvency(source: &AccountId, shortfall: Balance) {
+        if shortfall > 0 {
+            emit_event(
+                "insolvency_warning",
+                json!({ "source": source, "shortfall": U128(shortfall) }),
+            );
+        }
+    }
+
+    /// Deposit NEAR with the wrap contract and credit the account once wrapped
+    fn deposit_to_wrap_contract(&self, account_id: AccountId, amount: Balance) -> Promise {
+        ext_wnear::near_deposit(
+            self.wrap_contract.clone(),
+            amount,
+            5_000_000_000_000u64.into(),
+        )
+        .then(ext_self::wrap_callback(
+            account_id,
+            amount,
+            env::current_account_id(),
+            0,
+            5_000_000_000_000u64.into(),
+        ))
+    }
+
+    /// The fee taken by the treasury from an amount
+    fn fee(&self, amount: Balance) -> Balance {
+        self.rounding_mode.divide(
+            amount.saturating_mul(self.fee_basis_points as u128),
+            MAX_BASIS_POINTS as u128,
+        )
+    }
+
+    /// Try to transfer an amount of sNEAR from source to destination, less the fee which is
+    /// collected for the treasurer.  When pulled the amount waits in the claimable balance of
+    /// destination, otherwise it is pushed into its balance.  Nothing is touched for a zero amount
+    /// or when the source is the destination.
+    fn try_transfer(
+        &mut self,
+        source: AccountId,
+        destination: AccountId,
+        amount: Balance,
+        pull: bool,
+    ) -> Result<(), &'static str> {
+        if amount == 0 || source == destination {
+            return Ok(());
+        }
+        let balance_of_source = self.balances.get(&source).ok_or("source doesn't exist")?;
+        let new_balance_of_source = balance_of_source
+            .checked_sub(amount)
+            .ok_or("insufficient balance")?;
+
+        self.balances.insert(&source, &new_balance_of_source);
+
+        let fee = self.fee(amount);
+        self.fees_collected = self.fees_collected.saturating_add(fee);
+        if fee > 0 {
+            let day_index = env::block_timestamp() / SECONDS_PER_DAY;
+            let fees_on_day = self.fees_by_day.get(&day_index).unwrap_or_default();
+            self.fees_by_day
+                .insert(&day_index, &fees_on_day.saturating_add(fee));
+        }
+
+        let amount = amount - fee;
+        let credited = if pull {
+            &mut self.claimable
+        } else {
+            &mut self.balances
+        };
+        match credited.get(&destination) {
+            Some(current_balance) => {
+                credited.insert(&destination, &current_balance.saturating_add(amount))
+            }
+            None => credited.insert(&destination, &amount),
+        };
+        self.record_holder(&destination);
+
+        Ok(())
+    }
+
+    /// The time streams accrue up to, the time of freezing while accrual is frozen
+    fn accrual_timestamp(&self) -> Seconds {
+        let timestamp = env::block_timestamp();
+        self.frozen_at
+            .map_or(timestamp, |frozen_at| timestamp.min(frozen_at))
+    }
+
+    /// The `frozen_offset` of a subscription created now.  While frozen it is ahead of the
+    /// contract's by the frozen time already passed, so the subscription is only moved forward by
+    /// the time frozen after its creation.
+    fn creation_frozen_offset(&self) -> Seconds {
+        let frozen_duration = self.frozen_at.map_or(0, |frozen_at| {
+            env::block_timestamp().saturating_sub(frozen_at)
+        });
+        self.frozen_offset.saturating_add(frozen_duration)
+    }
+
+    /// The subscription moved forward by the time accrual has been frozen for since it was stored
+    fn thawed(&self, mut subscription: Subscription) -> Subscription {
+        subscription.thaw(self.frozen_offset);
+        subscription
+    }
+
+    /// Get a subscription by index, thawed
+    fn subscription(&self, subscription_index: SubscriptionIndex) -> Option<Subscription> {
+        self.subscriptions
+            .get(subscription_index)
+            .map(|subscription| self.thawed(subscription))
+    }
+
+    /// Try to get a subscription, thawed
+    fn try_subscription(&self, subscription_index: SubscriptionIndex) -> SubscriptionResult {
+        self.subscriptions
+            .try_get(subscription_index)
+            .map(|subscription| self.thawed(subscription))
+    }
+
+    /// Aggregate flows of the account, thawed
+    fn account_flow(&self, account_id: &AccountId) -> AccountFlow {
+        let mut account_flow = self.account_flows.get(account_id).unwrap_or_default();
+        account_flow.thaw(self.frozen_offset);
+        account_flow
+    }
+
+    /// Accrued but unsettled sNEAR across the subscriptions where the account is source, less
+    /// what their escrows cover, so what the account's balance has to cover
+    fn accrued_outgoing(&self, account_id: &AccountId) -> Balance {
+        let timestamp = self.accrual_timestamp();
+        self.subscriptions
+            .outputs
+            .get(account_id)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|subscription_index| {
+                self.subscription(*subscription_index)
+                    .map(|subscription| (*subscription_index, subscription))
+            })
+            .fold(0, |accrued: Balance, (subscription_index, subscription)| {
+                accrued.saturating_add(
+                    self.uncovered_by_escrow(subscription_index, subscription.accrued(timestamp)),
+                )
+            })
+    }
+
+    /// The part of an amount accrued by the subscription which its escrow, if any, doesn't cover
+    fn uncovered_by_escrow(
+        &self,
+        subscription_index: SubscriptionIndex,
+        accrued: Balance,
+    ) -> Balance {
+        accrued.saturating_sub(self.escrows.get(&subscription_index).unwrap_or_default())
+    }
+
+    /// Add the subscription to the aggregate flows of its source and destination
+    fn track(&mut self, subscription_index: SubscriptionIndex, subscription: &Subscription) {
+        let timestamp = self.accrual_timestamp();
+        let mut source_flow = self.account_flow(&subscription.source);
+        let mut destination_flow = self.account_flow(&subscription.destination);
+
+        if subscription.is_variable() {
+            source_flow.variable_subscriptions.push(subscription_index);
+            destination_flow
+                .variable_subscriptions
+                .push(subscription_index);
+        } else {
+            let accrued = subscription.accrued(timestamp);
+            source_flow.checkpoint(timestamp);
+            source_flow.outgoing_flow = source_flow.outgoing_flow.saturating_add(subscription.flow);
+            source_flow.outgoing_accrued = source_flow.outgoing_accrued.saturating_add(accrued);
+            destination_flow.checkpoint(timestamp);
+            destination_flow.incoming_flow = destination_flow
+                .incoming_flow
+                .saturating_add(subscription.flow);
+            destination_flow.incoming_accrued =
+                destination_flow.incoming_accrued.saturating_add(accrued);
+        }
+
+        self.account_flows
+            .insert(&subscription.source, &source_flow);
+        self.account_flows
+            .insert(&subscription.destination, &destination_flow);
+    }
+
+    /// Remove the subscription, as it is stored, from the aggregate flows of its source and
+    /// destination
+    fn untrack(&mut self, subscription_index: SubscriptionIndex, subscription: &Subscription) {
+        let timestamp = self.accrual_timestamp();
+        let mut source_flow = self.account_flow(&subscription.source);
+        let mut destination_flow = self.account_flow(&subscription.destination);
+
+        if subscription.is_variable() {
+            source_flow
+                .variable_subscriptions
+                .retain(|&variable| variable != subscription_index);
+            destination_flow
+                .variable_subscriptions
+                .retain(|&variable| variable != subscription_index);
+        } else {
+            let accrued = subscription.accrued(timestamp);
+            source_flow.checkpoint(timestamp);
+            source_flow.outgoing_flow = source_flow.outgoing_flow.saturating_sub(subscription.flow);
+            source_flow.outgoing_accrued = source_flow.outgoing_accrued.saturating_sub(accrued);
+            destination_flow.checkpoint(timestamp);
+            destination_flow.incoming_flow = destination_flow
+                .incoming_flow
+                .saturating_sub(subscription.flow);
+            destination_flow.incoming_accrued =
+                destination_flow.incoming_accrued.saturating_sub(accrued);
+        }
+
+        self.account_flows
+            .insert(&subscription.source, &source_flow);
+        self.account_flows
+            .insert(&subscription.destination, &destination_flow);
+    }
+
+    /// Register the destination with sNEAR if it isn't already, so streams only accrue to
+    /// registered accounts
+    fn register_destination(&mut self, destination: &AccountId) {
+        if !self.token.accounts.contains_key(destination) {
+            self.token.internal_register_account(destination);
+        }
+    }
+
+    /// Calculate the current balance in sNEAR for the account.  Destinations are registered when
+    /// a stream to them is created so any balance accrues to a registered account.
+    fn current_balance(&self, account_id: AccountId) -> U128 {
+        let balance = Yoctos(self.balances.get(&account_id).unwrap_or_default());
+        let timestamp = self.accrual_timestamp();
+        let account_flow = self.account_flow(&account_id);
+        let (incoming, outgoing) = account_flow.accrued(timestamp);
+        let (mut incoming, mut outgoing) = (Yoctos(incoming), Yoctos(outgoing));
+
+        // Subscriptions which don't stream at a constant rate are visited individually, what
+        // escrow covers isn't drawn from the source's balance
+        account_flow
+            .variable_subscriptions
+            .iter()
+            .filter_map(|subscription_index| {
+                self.subscription(*subscription_index)
+                    .map(|subscription| (*subscription_index, subscription))
+            })
+            .for_each(|(subscription_index, subscription)| {
+                let accrued = subscription.accrued(timestamp);
+                if subscription.destination == account_id {
+                    incoming = incoming
+                        .checked_add(Yoctos(accrued))
+                        .expect("balance overflows");
+                } else {
+                    let uncovered = self.uncovered_by_escrow(subscription_index, accrued);
+                    outgoing = outgoing
+                        .checked_add(Yoctos(uncovered))
+                        .expect("balance overflows");
+                }
+            });
+
+        // TODO check here the reserve amount??  Maybe it won't matter but to be sure
+        // An insolvent account has nothing left rather than a negative balance
+        balance
+            .checked_add(incoming)
+            .expect("balance overflows")
+            .checked_sub(outgoing)
+            .unwrap_or_default()
+            .0
+            .into()
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenCore for Paystream {
+    #[payable]
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>) {
+        self.token.ft_transfer(receiver_id, amount, memo)
+    }
+
+    #[payable]
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        self.token.ft_transfer_call(receiver_id, amount, memo, msg)
+    }
+
+    fn ft_total_supply(&self) -> U128 {
+        self.token.ft_total_supply()
+    }
+
+    fn ft_balance_of(&self, account_id: AccountId) -> U128 {
+        self.current_balance(account_id)
+    }
+}
+
+// Handlers for `FungibleTokenResolver`
+impl Paystream {
+    fn on_account_closed(&mut self, account_id: AccountId, balance: Balance) {
+        log!("Closed @{} with {}", account_id, balance);
+    }
+
+    fn on_tokens_burned(&mut self, account_id: AccountId, amount: Balance) {
+        log!("Account @{} burned {}", account_id, amount);
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for Paystream {
+    /// Credit the sender with sNEAR for the wNEAR transferred, scaled up to the decimals of sNEAR
+    /// if the wrap contract's token has fewer.  An empty message deposits it, otherwise the flow
+    /// of the sender's subscription is updated as the `TopUp` message asks.  The whole amount is
+    /// refunded by the wrap contract if the update fails, or if `wrap_near` hasn't yet verified
+    /// its decimals.
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        require!(
+            env::predecessor_account_id() == self.wrap_contract,
+            "only the wrap contract's token is accepted"
+        );
+        require!(
+            self.shutdown_phase == ShutdownPhase::Running,
+            "contract is shutting down"
+        );
+        require!(self.wrap_decimals.is_some(), "wrap contract not verified");
+        let amount = amount
+            .0
+            .checked_mul(self.wrap_scale())
+            .expect("amount overflows");
+        if msg.is_empty() {
+            self.credit_deposit(&sender_id, amount);
+        } else {
+            let topup: TopUp =
+                near_sdk::serde_json::from_str(&msg).expect("invalid top up message");
+            self.update_subscription_with_topup(
+                sender_id,
+                topup.subscription_index,
+                topup.new_flow.0,
+                amount,
+            );
+        }
+        PromiseOrValue::Value(U128(0))
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenResolver for Paystream {
+    #[private]
+    fn ft_resolve_transfer(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> U128 {
+        let (used_amount, burned_amount) =
+            self.token
+                .internal_ft_resolve_transfer(&sender_id, receiver_id, amount);
+        if burned_amount > 0 {
+            self.on_tokens_burned(sender_id, burned_amount);
+        }
+        used_amount.into()
+    }
+}
+
+near_contract_standards::impl_fungible_token_storage!(Paystream, token, on_account_closed);
+
+#[near_bindgen]
+impl FungibleTokenMetadataProvider for Paystream {
+    fn ft_metadata(&self) -> FungibleTokenMetadata {
+        self.metadata.get().unwrap()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::{accounts, get_created_receipts, get_logs, VMContextBuilder};
+    use near_sdk::{testing_env, RuntimeFeesConfig, VMConfig};
+
+    // Over the testnet we call `wrap`
+    const WRAP_CONTRACT: &str = "wrap.testnet";
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    #[test]
+    fn test_new() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        testing_env!(context.is_view(true).build());
+        assert_eq!(contract.ft_total_supply().0, 0);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 0);
+        assert_eq!(contract.owner(), &accounts(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "Permission required")]
+    fn test_caller_is_owner() {
+        let mut context = get_context(accounts(1));
+        Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        testing_env!(context
+            .is_view(false)
+            .predecessor_account_id(accounts(0))
+            .build());
+        Paystream::required(&accounts(0));
+        Paystream::required(&accounts(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Permission required")]
+    fn test_owner_signing_through_another_account() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.signer_account_id(accounts(0)).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.set_owner(accounts(1));
+    }
+
+    #[test]
+    fn test_create_subscription_through_contract_of_source() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.signer_account_id(accounts(3)).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        let subscription = contract.create_subscription(accounts(1), accounts(2), 100);
+        assert_eq!(subscription.source, accounts(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "caller must be source")]
+    fn test_create_subscription_signed_by_source_through_another_account() {
+        let mut context = get_context(accounts(3));
+        testing_env!(context.signer_account_id(accounts(1)).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "should be new owner")]
+    fn test_should_be_new_owner() {
+        let mut context = get_context(accounts(1));
+        let mut paystream = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        testing_env!(context
+            .is_view(false)
+            .predecessor_account_id(accounts(0))
+            .build());
+        paystream.set_owner(accounts(1));
+        testing_env!(context
+            .is_view(false)
+            .predecessor_account_id(accounts(1))
+            .build());
+        paystream.set_owner(accounts(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "should be new treasurer")]
+    fn test_should_be_new_treasurer() {
+        let mut context = get_context(accounts(1));
+        let mut paystream = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        testing_env!(context
+            .is_view(false)
+            .predecessor_account_id(accounts(0))
+            .build());
+        paystream.set_treasurer(accounts(1));
+        paystream.set_treasurer(accounts(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "The contract is not initialized")]
+    fn test_default() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let _contract = Paystream::default();
+    }
+
+    #[test]
+    #[should_panic(expected = "Subscription [1] not present")]
+    fn test_livecycle_of_subscription() {
+        let mut context = get_context(accounts(1));
+        let block_timestamp = 10;
+        let flow = 100;
+        testing_env!(context.block_timestamp(block_timestamp).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        let subscription = contract.create_subscription(accounts(1), accounts(2), flow);
+        assert_eq!(subscription.source, accounts(1));
+        assert_eq!(subscription.destination, accounts(2));
+        assert_eq!(subscription.flow, flow);
+        assert_eq!(subscription.timestamp, block_timestamp);
+
+        let subscriptions = contract.subscriptions_by_account();
+        let new_subscription = contract.get_subscription(subscriptions[0]);
+        assert_eq!(
+            new_subscription, subscription,
+            "what is created isn't what is stored"
+        );
+
+        let updated_subscription = contract.update_subscription(subscriptions[0], 200);
+        assert_eq!(
+            updated_subscription.flow, 200,
+            "rate should have been updated"
+        );
+
+        testing_env!(context
+            .block_timestamp(updated_subscription.reserve_unlocked_at())
+            .build());
+        contract.remove_subscription(subscriptions[0]);
+        contract.get_subscription(subscriptions[0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "source must not be destination")]
+    fn test_should_fail_source_must_not_be_destination() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.create_subscription(accounts(1), accounts(1), 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "rate needs to be greater than zero")]
+    fn test_rate_needs_to_be_greater_than_zero() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.create_subscription(accounts(1), accounts(2), 0);
+    }
+
+    #[test]
+    fn test_flow_at_and_above_minimum() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(1), WRAP_CONTRACT.parse().unwrap());
+        contract.update_min_flow(100);
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        let subscription = contract.create_subscription(accounts(1), accounts(2), 100);
+        assert_eq!(subscription.flow, 100);
+        let subscription = contract.create_subscription(accounts(1), accounts(3), 101);
+        assert_eq!(subscription.flow, 101);
+    }
+
+    #[test]
+    #[should_panic(expected = "flow below minimum")]
+    fn test_flow_below_minimum() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(1), WRAP_CONTRACT.parse().unwrap());
+        contract.update_min_flow(100);
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 99);
+    }
+
+    #[test]
+    #[should_panic(expected = "flow below minimum")]
+    fn test_update_flow_below_minimum() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(1), WRAP_CONTRACT.parse().unwrap());
+        contract.update_min_flow(100);
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let subscriptions = contract.subscriptions_by_account();
+        contract.update_subscription(subscriptions[0], 99);
+    }
+
+    #[test]
+    #[should_panic(expected = "caller must be source")]
+    fn test_update_subscription_by_destination() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 1);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.update_subscription(contract.subscriptions.subscription_index, 100_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "caller must be source")]
+    fn test_update_subscription_by_stranger() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 1);
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.update_subscription(contract.subscriptions.subscription_index, 100_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "sufficient reserve is required")]
+    fn test_update_subscription_beyond_reserve() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.reserve = 1_000;
+        contract.balances.insert(&accounts(1), &200_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.update_subscription(contract.subscriptions.subscription_index, 200);
+    }
+
+    #[test]
+    #[should_panic(expected = "subscription not active")]
+    fn test_update_unacknowledged_subscription() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.set_acknowledgment_required(true);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.update_subscription(contract.subscriptions.subscription_index, 200);
+    }
+
+    #[test]
+    #[should_panic(expected = "subscription not active")]
+    fn test_update_ended_subscription() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription_until(accounts(1), accounts(2), 100, 20);
+        testing_env!(context.block_timestamp(20).build());
+        contract.update_subscription(contract.subscriptions.subscription_index, 200);
+    }
+
+    #[test]
+    fn test_stored_balance() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+
+        testing_env!(context.block_timestamp(20).build());
+        assert_eq!(contract.stored_balance(accounts(1)).0, 1_000_000_000);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 999_999_000);
+
+        contract.settle_partial(contract.subscriptions.subscription_index);
+        assert_eq!(contract.stored_balance(accounts(1)).0, 999_999_000);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 999_999_000);
+    }
+
+    #[test]
+    fn test_transfer_incoming() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let subscription_index = contract.subscriptions_by_account()[0];
+
+        testing_env!(context
+            .block_timestamp(20)
+            .predecessor_account_id(accounts(2))
+            .build());
+        let subscription = contract.transfer_incoming(subscription_index, accounts(3));
+        assert_eq!(subscription.destination, accounts(3));
+        assert_eq!(subscription.timestamp, 20);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 1_000);
+        assert!(contract.subscriptions_by_account().is_empty());
+
+        testing_env!(context
+            .block_timestamp(30)
+            .predecessor_account_id(accounts(3))
+            .build());
+        assert_eq!(
+            contract.subscriptions_by_account(),
+            vec![subscription_index]
+        );
+        assert_eq!(contract.claimable_of(accounts(2)).0, 1_000);
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 1_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "account is frozen")]
+    fn test_transfer_incoming_to_frozen_account() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.freeze_account(accounts(3));
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.transfer_incoming(contract.subscriptions.subscription_index, accounts(3));
+    }
+
+    #[test]
+    fn test_transfer_incoming_awaits_acknowledgment() {
+        let mut context = get_context(accounts(3));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.set_acknowledgment_required(true);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+
+        testing_env!(context
+            .block_timestamp(20)
+            .predecessor_account_id(accounts(2))
+            .build());
+        let subscription = contract.transfer_incoming(subscription_index, accounts(3));
+        assert!(!subscription.active);
+
+        testing_env!(context
+            .block_timestamp(30)
+            .predecessor_account_id(accounts(3))
+            .build());
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 0);
+        contract.acknowledge_subscription(subscription_index);
+        testing_env!(context.block_timestamp(40).build());
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 1_000);
+    }
+
+    #[test]
+    fn test_transfer_incoming_resets_destination_choices() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.approve_end_time(subscription_index, 50);
+        contract.set_notify(subscription_index, Some(accounts(4)));
+        contract.set_settlement_mode(subscription_index, false);
+
+        testing_env!(context.block_timestamp(20).build());
+        let subscription = contract.transfer_incoming(subscription_index, accounts(3));
+        assert_eq!(subscription.notify, None);
+        assert!(subscription.pull);
+        assert_eq!(contract.end_time_approvals.get(&subscription_index), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "subscription limit reached")]
+    fn test_transfer_incoming_beyond_subscription_limit() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.update_max_subscriptions_per_account(1);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.balances.insert(&accounts(4), &1_000_000_000);
+        contract.create_subscription(accounts(4), accounts(3), 100);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.transfer_incoming(1, accounts(3));
+    }
+
+    #[test]
+    #[should_panic(expected = "destination inflow cap exceeded")]
+    fn test_transfer_incoming_beyond_inflow_cap() {
+        let mut context = get_context(accounts(3));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.set_max_incoming_flow(Some(50));
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.transfer_incoming(contract.subscriptions.subscription_index, accounts(3));
+    }
+
+    #[test]
+    #[should_panic(expected = "caller must be destination")]
+    fn test_transfer_incoming_caller_must_be_destination() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let subscription_index = contract.subscriptions_by_account()[0];
+        contract.transfer_incoming(subscription_index, accounts(3));
+    }
+
+    #[test]
+    fn test_total_available() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.balances.insert(&accounts(2), &500);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+
+        testing_env!(context.block_timestamp(20).build());
+        contract.update_subscription(subscription_index, 200);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 1_000);
+
+        testing_env!(context.block_timestamp(30).build());
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 2_500);
+        assert_eq!(contract.total_available(accounts(2)).0, 3_500);
+    }
+
+    #[test]
+    fn test_settlement_is_claimable() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let subscription_index = contract.subscriptions_by_account()[0];
+
+        testing_env!(context.block_timestamp(20).build());
+        contract.update_subscription(subscription_index, 200);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 1_000);
+        assert_eq!(contract.balances.get(&accounts(2)), None);
+        assert_eq!(contract.balances.get(&accounts(1)), Some(999_999_000));
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        assert_eq!(contract.claim().0, 1_000);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 0);
+        assert_eq!(contract.balances.get(&accounts(2)), Some(1_000));
+        assert_eq!(contract.claim().0, 0);
+        assert_eq!(contract.balances.get(&accounts(2)), Some(1_000));
+    }
+
+    #[test]
+    fn test_update_reserve_and_audit() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(1), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        let mut subscriptions = vec![];
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        subscriptions.push(contract.subscriptions.subscription_index);
+        contract.create_subscription(accounts(1), accounts(3), 1_000);
+        subscriptions.push(contract.subscriptions.subscription_index);
+
+        assert!(contract.update_reserve_and_audit(60 * 60, 0, 10).is_empty());
+        assert_eq!(
+            contract.update_reserve_and_audit(1_000_000, 0, 10),
+            vec![subscriptions[1]]
+        );
+        assert_eq!(
+            contract.update_reserve_and_audit(10_000_000, 0, 10),
+            subscriptions
+        );
+        assert_eq!(
+            contract.update_reserve_and_audit(10_000_000, subscriptions[1], 1),
+            vec![subscriptions[1]]
+        );
+        assert!(contract
+            .update_reserve_and_audit(10_000_000, subscriptions[1] + 1, 10)
+            .is_empty());
+        // A window from zero covers the indices below the limit, continuing from the limit
+        assert_eq!(
+            contract.update_reserve_and_audit(10_000_000, 0, 2),
+            vec![subscriptions[0]]
+        );
+        assert_eq!(
+            contract.update_reserve_and_audit(10_000_000, 2, 2),
+            vec![subscriptions[1]]
+        );
+        assert_eq!(contract.reserve, 10_000_000);
+        assert!(contract.subscriptions.exists(subscriptions[0]));
+        assert!(contract.subscriptions.exists(subscriptions[1]));
+    }
+
+    #[test]
+    #[should_panic(expected = "create cooldown has not elapsed")]
+    fn test_create_within_cooldown() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(1), WRAP_CONTRACT.parse().unwrap());
+        contract.update_create_cooldown(60);
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        testing_env!(context.block_timestamp(69).build());
+        contract.create_subscription(accounts(1), accounts(3), 100);
+    }
+
+    #[test]
+    fn test_create_after_cooldown() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(1), WRAP_CONTRACT.parse().unwrap());
+        contract.update_create_cooldown(60);
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        testing_env!(context.block_timestamp(70).build());
+        contract.create_subscription(accounts(1), accounts(3), 100);
+        assert_eq!(contract.subscriptions_by_account().len(), 2);
+        assert_eq!(contract.last_create.get(&accounts(1)), Some(70));
+    }
+
+    #[test]
+    fn test_wrap_callback_failed_refunds() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        testing_env!(
+            context.build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        contract.wrap_callback(accounts(1), 1_000);
+
+        assert_eq!(contract.balances.get(&accounts(1)), None);
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].receiver_id, accounts(1));
+        assert_eq!(
+            receipts[0].actions,
+            vec![VmAction::Transfer { deposit: 1_000 }]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Permission required")]
+    fn test_wrap_callback_by_other_caller() {
+        let context = get_context(accounts(1));
+        testing_env!(
+            context.build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.wrap_callback(accounts(1), 1_000);
+    }
+
+    #[test]
+    fn test_wrap_contract() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let contract = Paystream::new(accounts(0), accounts(3));
+        assert_eq!(contract.wrap_contract(), &accounts(3));
+    }
+
+    #[test]
+    fn test_wrap_near_targets_wrap_contract() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.attached_deposit(1_000).build());
+        let mut contract = Paystream::new(accounts(0), accounts(3));
+
+        // Unverified, the metadata of the wrap contract is checked first
+        contract.wrap_near();
+        contract.wrap_decimals = Some(DECIMALS);
+        contract.wrap_near();
+
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 4);
+        assert_eq!(receipts[0].receiver_id, accounts(3));
+        assert!(matches!(
+            &receipts[0].actions[0],
+            VmAction::FunctionCall { function_name, .. } if function_name == "ft_metadata"
+        ));
+        assert_eq!(receipts[2].receiver_id, accounts(3));
+        assert!(matches!(
+            &receipts[2].actions[0],
+            VmAction::FunctionCall { function_name, deposit: 1_000, .. }
+                if function_name == "near_deposit"
+        ));
+    }
+
+    #[test]
+    fn test_notification_target() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.set_notification_target(Some(accounts(3)));
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.set_notify(2, Some(accounts(4)));
+
+        testing_env!(context.block_timestamp(20).build());
+        contract.settle_partial(1);
+        contract.settle_partial(2);
+
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 2);
+        assert_eq!(receipts[0].receiver_id, accounts(3));
+        assert!(matches!(
+            &receipts[0].actions[0],
+            VmAction::FunctionCall { function_name, .. } if function_name == "on_settlement"
+        ));
+        assert_eq!(receipts[1].receiver_id, accounts(4));
+    }
+
+    #[test]
+    fn test_unwrap_callback_failed_restores_balance() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000);
+        contract.unwrap_near(400);
+        assert_eq!(contract.balances.get(&accounts(1)), Some(600));
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        contract.unwrap_callback(accounts(1), 400);
+        assert_eq!(contract.balances.get(&accounts(1)), Some(1_000));
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.set_fee_basis_points(500);
+        contract.reserve = 0;
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.balances.insert(&accounts(1), &1_000_000);
+        contract.balances.insert(&accounts(3), &500);
+        contract.streaming_balance = 1_000_500;
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.create_subscription(accounts(1), accounts(3), 100);
+        contract.remove_subscription(2);
+        contract.create_subscription(accounts(1), accounts(3), 100);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(20)
+            .build());
+        contract.initiate_shutdown();
+        assert_eq!(contract.shutdown_phase(), ShutdownPhase::Settling);
+
+        testing_env!(context.block_timestamp(30).build());
+        assert_eq!(contract.advance_shutdown(2), ShutdownPhase::Settling);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 950);
+        assert_eq!(contract.advance_shutdown(2), ShutdownPhase::Refunding);
+        assert_eq!(contract.claimable_of(accounts(3)).0, 950);
+        assert_eq!(contract.stats().active_subscriptions, 0);
+        assert_eq!(contract.accumulated_fees().0, 0);
+        assert_eq!(contract.balances.get(&accounts(0)), Some(100));
+
+        assert_eq!(
+            contract.refund_accounts(vec![accounts(1), accounts(2)]),
+            ShutdownPhase::Refunding
+        );
+        assert_eq!(
+            contract.refund_accounts(vec![accounts(3), accounts(0)]),
+            ShutdownPhase::Closed
+        );
+        for account_id in [accounts(0), accounts(1), accounts(2), accounts(3)] {
+            assert_eq!(contract.balances.get(&account_id), None);
+            assert_eq!(contract.claimable_of(account_id).0, 0);
+        }
+        assert_eq!(contract.stats().streaming_balance.0, 0);
+        contract.delete_contract(accounts(0));
+    }
+
+    #[test]
+    fn test_shutdown_closes_only_once_holders_are_refunded() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        // A balance the streaming balance never counted
+        contract.balances.insert(&accounts(1), &1_000_000);
+        contract.record_holder(&accounts(1));
+        contract.initiate_shutdown();
+        assert_eq!(contract.advance_shutdown(10), ShutdownPhase::Refunding);
+        assert_eq!(contract.stats().streaming_balance.0, 0);
+
+        assert_eq!(contract.refund_accounts(vec![]), ShutdownPhase::Refunding);
+        assert_eq!(
+            contract.refund_accounts(vec![accounts(1)]),
+            ShutdownPhase::Closed
+        );
+        assert_eq!(contract.balances.get(&accounts(1)), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "contract is shutting down")]
+    fn test_create_while_shutting_down() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.initiate_shutdown();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.balances.insert(&accounts(1), &1_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "shutdown not closed")]
+    fn test_delete_contract_before_closed() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.initiate_shutdown();
+        contract.delete_contract(accounts(0));
+    }
+
+    fn poke_with_price(
+        context: &mut VMContextBuilder,
+        contract: &mut Paystream,
+        subscription_index: SubscriptionIndex,
+        price: u128,
+    ) {
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(
+                near_sdk::serde_json::to_vec(&U128(price)).unwrap()
+            )],
+        );
+        contract.poke_callback(subscription_index);
+    }
+
+    #[test]
+    fn test_conditional_subscription() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_conditional_subscription(
+            accounts(2),
+            100,
+            accounts(3),
+            PriceCondition::Above(U128(1_000)),
+        );
+        let subscription_index = contract.subscriptions.subscription_index;
+
+        testing_env!(context.block_timestamp(20).build());
+        contract.poke(subscription_index);
+        assert_eq!(contract.current_balance(accounts(2)).0, 0);
+        poke_with_price(&mut context, &mut contract, subscription_index, 1_001);
+
+        testing_env!(context.block_timestamp(30).build());
+        assert_eq!(contract.current_balance(accounts(2)).0, 1_000);
+        poke_with_price(&mut context, &mut contract, subscription_index, 900);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 1_000);
+
+        testing_env!(context.block_timestamp(50).build());
+        assert_eq!(contract.current_balance(accounts(2)).0, 0);
+        poke_with_price(&mut context, &mut contract, subscription_index, 1_500);
+        testing_env!(context.block_timestamp(55).build());
+        assert_eq!(contract.current_balance(accounts(2)).0, 500);
+    }
+
+    #[test]
+    fn test_balance_snapshot() {
+        let mut context = get_context(accounts(0));
+        testing_env!(
+            context.build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.wrap_callback(accounts(1), 1_000_000_000);
+        contract.wrap_callback(accounts(3), 500);
+        contract.wrap_callback(accounts(1), 1_000);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .block_timestamp(10)
+            .build());
+        contract.settle_partial(1);
+        contract.claim();
+
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        assert_eq!(
+            contract.balance_snapshot(0, 10),
+            vec![
+                (accounts(1), U128(1_000_000_000)),
+                (accounts(3), U128(500)),
+                (accounts(2), U128(1_000)),
+            ]
+        );
+        assert_eq!(
+            contract.balance_snapshot(1, 1),
+            vec![(accounts(3), U128(500))]
+        );
+    }
+
+    #[test]
+    fn test_check_invariants() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.reserve = 0;
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        assert!(contract.check_invariants());
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.create_subscription(accounts(1), accounts(3), 100);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.remove_subscription(2);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.transfer_incoming(1, accounts(3));
+        assert!(contract.check_invariants());
+    }
+
+    #[test]
+    fn test_check_invariants_with_dangling_index() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract
+            .subscriptions
+            .inputs
+            .insert(&accounts(2), &vec![1, 2]);
+        assert!(!contract.check_invariants());
+    }
+
+    #[test]
+    fn test_check_invariants_with_duplicate_index() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract
+            .subscriptions
+            .outputs
+            .insert(&accounts(1), &vec![1, 1]);
+        assert!(!contract.check_invariants());
+    }
+
+    #[test]
+    fn test_scaled_subscription() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        // 50,000 yoctos a day is less than a yocto a second
+        let daily = 50_000;
+        assert_eq!(FlowUnit::PerDay.to_flow(daily), 0);
+        let scaled_flow = daily * FLOW_SCALE / SECONDS_PER_DAY as u128;
+        let subscription = contract.create_subscription_scaled(accounts(2), U128(scaled_flow));
+        assert_eq!(subscription.flow, 0);
+        let subscription_index = contract.subscriptions.subscription_index;
+
+        testing_env!(context.block_timestamp(10 + SECONDS_PER_DAY / 2).build());
+        contract.settle_partial(subscription_index);
+        testing_env!(context.block_timestamp(10 + SECONDS_PER_DAY).build());
+        assert_eq!(
+            contract.claimable_of(accounts(2)).0 + contract.current_balance(accounts(2)).0,
+            49_999
+        );
+        contract.settle_partial(subscription_index);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 49_999);
+        assert_eq!(
+            contract.balances.get(&accounts(1)),
+            Some(1_000_000_000 - 49_999)
+        );
+    }
+
+    #[test]
+    fn test_expiring_before() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.create_subscription_until(accounts(1), accounts(3), 100, 100);
+        let bounded = contract.subscriptions.subscription_index;
+        contract.create_subscription_until(accounts(1), accounts(4), 100, 500);
+
+        assert_eq!(contract.expiring_before(accounts(1), 200), vec![bounded]);
+        assert_eq!(contract.expiring_before(accounts(3), 200), vec![bounded]);
+        assert!(contract.expiring_before(accounts(1), 100).is_empty());
+        assert_eq!(contract.expiring_before(accounts(1), 1_000).len(), 2);
+
+        testing_env!(context.block_timestamp(1_000).build());
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 9_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "account is frozen")]
+    fn test_frozen_destination() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.freeze_account(accounts(2));
+        assert!(contract.is_frozen(accounts(2)));
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.create_subscription(accounts(1), accounts(2), 100);
+    }
+
+    #[test]
+    fn test_unfrozen_destination() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.freeze_account(accounts(2));
+        contract.unfreeze_account(accounts(2));
+        assert!(!contract.is_frozen(accounts(2)));
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        let subscription = contract.create_subscription(accounts(1), accounts(2), 100);
+        assert_eq!(subscription.destination, accounts(2));
+    }
+
+    #[test]
+    fn test_outstanding_debt() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.create_subscription(accounts(1), accounts(3), 50);
+        contract.balances.insert(&accounts(1), &1_000);
+        assert_eq!(contract.outstanding_debt(accounts(1)).0, 0);
+
+        testing_env!(context.block_timestamp(20).build());
+        assert_eq!(contract.outstanding_debt(accounts(1)).0, 500);
+        assert_eq!(contract.outstanding_debt(accounts(2)).0, 0);
+    }
+
+    #[test]
+    fn test_total_obligation() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.create_subscription(accounts(1), accounts(3), 50);
+        contract.create_subscription_until(accounts(1), accounts(2), 10, 15);
+        assert_eq!(contract.total_obligation(accounts(1)).0, 0);
+
+        testing_env!(context.block_timestamp(20).build());
+        assert_eq!(contract.total_obligation(accounts(1)).0, 1_550);
+        contract.settle_partial(1);
+        assert_eq!(contract.total_obligation(accounts(1)).0, 550);
+        assert_eq!(contract.total_obligation(accounts(2)).0, 0);
+    }
+
+    #[test]
+    fn test_escrowed_subscription() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_escrowed_subscription(accounts(2), 100, 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+        assert_eq!(contract.balances.get(&accounts(1)), Some(999_990_000));
+
+        // The source spends its balance, the escrow still pays the destination
+        contract.balances.insert(&accounts(1), &0);
+        testing_env!(context.block_timestamp(60).build());
+        contract.settle_partial(subscription_index);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 5_000);
+        testing_env!(context.block_timestamp(200).build());
+        contract.settle_partial(subscription_index);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 10_000);
+        assert_eq!(contract.balances.get(&accounts(1)), Some(0));
+    }
+
+    #[test]
+    fn test_escrow_covers_accrual() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_escrowed_subscription(accounts(2), 10, 100);
+        contract.balances.insert(&accounts(1), &300);
+
+        testing_env!(context.block_timestamp(60).build());
+        assert_eq!(contract.total_obligation(accounts(1)).0, 0);
+        assert_eq!(contract.outstanding_debt(accounts(1)).0, 0);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 300);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 500);
+    }
+
+    #[test]
+    fn test_clawback_subscription() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+
+        testing_env!(context.block_timestamp(20).build());
+        contract.settle_partial(subscription_index);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 1_000);
+
+        testing_env!(context.block_timestamp(30).build());
+        contract.clawback_subscription(subscription_index);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 0);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 0);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 1_000_000_000);
+        assert!(contract.subscriptions.get(subscription_index).is_none());
+        assert_eq!(contract.total_active().0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "clawback window has passed")]
+    fn test_clawback_after_window() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+
+        testing_env!(context
+            .block_timestamp(10 + DEFAULT_CLAWBACK_SECONDS)
+            .build());
+        contract.clawback_subscription(contract.subscriptions.subscription_index);
+    }
+
+    #[test]
+    fn test_clawback_with_incoming_split() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.claimable.insert(&accounts(2), &5_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.set_incoming_split(vec![(accounts(3), 1)]);
+        testing_env!(context.block_timestamp(20).build());
+        contract.settle_partial(subscription_index);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 5_000);
+        assert_eq!(contract.claimable_of(accounts(3)).0, 1_000);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.clawback_subscription(subscription_index);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 5_000);
+        assert_eq!(contract.claimable_of(accounts(3)).0, 0);
+        assert_eq!(contract.balances.get(&accounts(1)), Some(1_000_000_000));
+    }
+
+    #[test]
+    fn test_clawback_reverses_each_settlement_net_of_its_fee() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.set_fee_basis_points(25);
+        contract.set_rounding_mode(RoundingMode::HalfUp);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 20);
+        let subscription_index = contract.subscriptions.subscription_index;
+
+        testing_env!(context.block_timestamp(20).build());
+        contract.settle_partial(subscription_index);
+        testing_env!(context.block_timestamp(30).build());
+        contract.settle_partial(subscription_index);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 398);
+
+        contract.clawback_subscription(subscription_index);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 0);
+        assert_eq!(contract.balances.get(&accounts(1)), Some(999_999_998));
+    }
+
+    #[test]
+    #[should_panic(expected = "settled funds have been withdrawn")]
+    fn test_clawback_after_destination_withdrew() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+
+        testing_env!(context.block_timestamp(20).build());
+        contract.settle_partial(subscription_index);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.claim();
+        contract.unwrap_near(1_000);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.clawback_subscription(subscription_index);
+    }
+
+    #[test]
+    fn test_remove_group() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.reserve = 0;
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        let group_id = contract.create_group();
+        for destination in [accounts(2), accounts(3), accounts(4)] {
+            contract.create_subscription(accounts(1), destination, 100);
+            contract.add_to_group(group_id, contract.subscriptions.subscription_index);
+        }
+        contract.create_subscription(accounts(1), accounts(5), 100);
+
+        testing_env!(context.block_timestamp(20).build());
+        assert_eq!(contract.remove_group(group_id), 3);
+        assert_eq!(contract.total_active().0, 1);
+        for destination in [accounts(2), accounts(3), accounts(4)] {
+            assert_eq!(contract.claimable_of(destination).0, 1_000);
+        }
+        assert!(contract.subscriptions.get(4).is_some());
+    }
+
+    #[test]
+    fn test_remove_group_within_reserve_lock() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.reserve = 0;
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        let group_id = contract.create_group();
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.add_to_group(group_id, 1);
+        contract.reserve = 100;
+        contract.create_subscription(accounts(1), accounts(3), 100);
+        contract.add_to_group(group_id, 2);
+        let unlocked_at = contract.get_subscription(2).reserve_unlocked_at();
+
+        testing_env!(context.block_timestamp(20).build());
+        assert_eq!(contract.remove_group(group_id), 1);
+        assert!(contract.subscriptions.get(1).is_none());
+        assert_eq!(contract.groups.get(&group_id), Some(vec![2]));
+
+        testing_env!(context.block_timestamp(unlocked_at).build());
+        assert_eq!(contract.remove_group(group_id), 1);
+        assert_eq!(contract.groups.get(&group_id), None);
+        assert_eq!(contract.group_owners.get(&group_id), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "caller must own group")]
+    fn test_add_to_group_of_another() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(2), &1_000_000_000);
+        let group_id = contract.create_group();
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.create_subscription(accounts(2), accounts(3), 100);
+        contract.add_to_group(group_id, contract.subscriptions.subscription_index);
+    }
+
+    #[test]
+    fn test_incoming_split() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.set_incoming_split(vec![(accounts(2), 2), (accounts(3), 1), (accounts(4), 1)]);
+
+        testing_env!(context.block_timestamp(30).build());
+        contract.settle_partial(subscription_index);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 1_000);
+        assert_eq!(contract.claimable_of(accounts(3)).0, 500);
+        assert_eq!(contract.claimable_of(accounts(4)).0, 500);
+
+        contract.set_incoming_split(vec![]);
+        testing_env!(context.block_timestamp(40).build());
+        contract.settle_partial(subscription_index);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 2_000);
+        assert_eq!(contract.claimable_of(accounts(3)).0, 500);
+    }
+
+    #[test]
+    fn test_incoming_split_rounded_up() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.set_rounding_mode(RoundingMode::HalfUp);
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.create_subscription(accounts(1), accounts(2), 1);
+        let subscription_index = contract.subscriptions.subscription_index;
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.set_incoming_split(vec![(accounts(3), 1), (accounts(4), 1)]);
+
+        testing_env!(context.block_timestamp(15).build());
+        contract.settle_partial(subscription_index);
+        assert_eq!(contract.claimable_of(accounts(3)).0, 3);
+        assert_eq!(contract.claimable_of(accounts(4)).0, 2);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "split weight must be greater than zero")]
+    fn test_incoming_split_with_zero_weight() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.set_incoming_split(vec![(accounts(3), 0)]);
+    }
+
+    #[test]
+    fn test_passthrough() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.create_passthrough(subscription_index, accounts(3), 2_500);
+
+        testing_env!(context.block_timestamp(30).build());
+        contract.settle_partial(subscription_index);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 1_500);
+        assert_eq!(contract.claimable_of(accounts(3)).0, 500);
+    }
+
+    #[test]
+    #[should_panic(expected = "caller must be destination")]
+    fn test_passthrough_by_source() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.create_passthrough(
+            contract.subscriptions.subscription_index,
+            accounts(3),
+            2_500,
+        );
+    }
+
+    #[test]
+    fn test_escrow_balance() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_escrowed_subscription(accounts(2), 100, 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+        assert_eq!(contract.escrow_balance(subscription_index).0, 10_000);
+
+        testing_env!(context.block_timestamp(35).build());
+        contract.settle_partial(subscription_index);
+        assert_eq!(contract.escrow_balance(subscription_index).0, 7_500);
+
+        testing_env!(context.block_timestamp(110).build());
+        contract.settle_partial(subscription_index);
+        assert_eq!(contract.escrow_balance(subscription_index).0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "insufficient balance")]
+    fn test_escrowed_funds_cannot_be_unwrapped() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_escrowed_subscription(accounts(2), 100, 100);
+        contract.unwrap_near(1_000_000_000);
+    }
+
+    #[test]
+    fn test_escrow_returned_on_removal() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_escrowed_subscription(accounts(2), 100, 100);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .block_timestamp(40)
+            .build());
+        contract.remove_subscription(contract.subscriptions.subscription_index);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 3_000);
+        assert_eq!(contract.balances.get(&accounts(1)), Some(999_997_000));
+    }
+
+    #[test]
+    fn test_strict_removal_covered_by_escrow() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_escrowed_subscription(accounts(2), 10, 100);
+        contract.balances.insert(&accounts(1), &0);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .block_timestamp(60)
+            .build());
+        contract.remove_subscription_strict(contract.subscriptions.subscription_index);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 500);
+        assert_eq!(contract.balances.get(&accounts(1)), Some(500));
+    }
+
+    #[test]
+    fn test_funding_for_runway_below_target() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.create_subscription(accounts(1), accounts(3), 50);
+        contract.balances.insert(&accounts(1), &10_000);
+
+        testing_env!(context.block_timestamp(20).build());
+        // 8_500 remains after accrual, 100 seconds at 150 needs 15_000
+        assert_eq!(contract.funding_for_runway(accounts(1), 100).0, 6_500);
+    }
+
+    #[test]
+    fn test_funding_for_runway_above_target() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        assert_eq!(contract.funding_for_runway(accounts(1), 100).0, 0);
+        assert_eq!(contract.funding_for_runway(accounts(2), 100).0, 0);
+    }
+
+    #[test]
+    fn test_is_solvent() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        assert!(solvent_holding(&mut context, &contract, 0));
+        testing_env!(
+            context.build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        contract.wrap_callback(accounts(1), 1_000_000_000);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        testing_env!(context.block_timestamp(20).build());
+        contract.settle_partial(1);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.claim();
+        assert!(solvent_holding(&mut context, &contract, 1_000_000_000));
+
+        // The wrap contract holds less wNEAR than the sNEAR credited
+        assert!(!solvent_holding(&mut context, &contract, 999_999_999));
+    }
+
+    /// Whether the contract is solvent when the wrap contract reports it holds the balance
+    fn solvent_holding(
+        context: &mut VMContextBuilder,
+        contract: &Paystream,
+        held: Balance,
+    ) -> bool {
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(
+                near_sdk::serde_json::to_vec(&U128(held)).unwrap()
+            )],
+        );
+        contract.solvency_callback()
+    }
+
+    #[test]
+    fn test_settle_partial() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+        contract.balances.insert(&accounts(1), &1_050);
+
+        testing_env!(context.block_timestamp(30).build());
+        assert_eq!(contract.settle_partial(subscription_index).0, 1_000);
+        assert_eq!(contract.get_subscription(subscription_index).timestamp, 20);
+        assert_eq!(contract.balances.get(&accounts(1)), Some(50));
+        assert_eq!(contract.claimable_of(accounts(2)).0, 1_000);
+
+        assert_eq!(contract.settle_partial(subscription_index).0, 1_000);
+        assert_eq!(contract.get_subscription(subscription_index).timestamp, 20);
+        assert_eq!(contract.balances.get(&accounts(1)), Some(50));
+    }
+
+    #[test]
+    fn test_insolvency_warning() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.balances.insert(&accounts(1), &1_050);
+
+        testing_env!(context.block_timestamp(30).build());
+        contract.settle_partial(1);
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"paystream","version":"1.0.0","event":"insolvency_warning","data":[{"source":"bob","shortfall":"1000"}]}"#
+            ]
+        );
+    }
+
+    #[test]
+    fn test_settle_with_replayed_nonce() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+
+        testing_env!(context.block_timestamp(20).build());
+        assert_eq!(contract.settle_with_nonce(1, 7), Some(U128(0)));
+        assert_eq!(contract.claimable_of(accounts(2)).0, 1_000);
+
+        testing_env!(context.block_timestamp(30).build());
+        assert_eq!(contract.settle_with_nonce(1, 7), None);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 1_000);
+        assert_eq!(contract.get_subscription(1).timestamp, 20);
+
+        assert_eq!(contract.settle_with_nonce(1, 8), Some(U128(0)));
+        assert_eq!(contract.claimable_of(accounts(2)).0, 2_000);
+    }
+
+    #[test]
+    fn test_settle_with_earlier_nonce() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+
+        testing_env!(context.block_timestamp(20).build());
+        assert_eq!(contract.settle_with_nonce(1, 7), Some(U128(0)));
+        assert_eq!(contract.settle_with_nonce(1, 8), Some(U128(0)));
+
+        testing_env!(context.block_timestamp(30).build());
+        assert_eq!(contract.settle_with_nonce(1, 7), None);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 1_000);
+    }
+
+    #[test]
+    fn test_clock_going_backwards() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(100).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription_until(accounts(1), accounts(2), 100, 1_000);
+        let subscription_index = contract.subscriptions.subscription_index;
+
+        testing_env!(context.block_timestamp(50).build());
+        assert_eq!(contract.settle_partial(subscription_index).0, 0);
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"paystream","version":"1.0.0","event":"clock_anomaly","data":[{"timestamp":50,"settled_at":100}]}"#
+            ]
+        );
+        assert_eq!(contract.get_subscription(subscription_index).timestamp, 100);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 0);
+
+        testing_env!(context.block_timestamp(150).build());
+        contract.settle_partial(subscription_index);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 5_000);
+    }
+
+    #[test]
+    fn test_settle_partial_when_solvent() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+
+        testing_env!(context.block_timestamp(30).build());
+        assert_eq!(contract.settle_partial(subscription_index).0, 0);
+        assert_eq!(contract.get_subscription(subscription_index).timestamp, 30);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 2_000);
+    }
+
+    #[test]
+    fn test_templates() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.save_template("payroll".into(), 100, Some(50));
+        assert_eq!(
+            contract.get_template(accounts(1), "payroll".into()),
+            Some(Template {
+                flow: 100,
+                end_offset: Some(50)
+            })
+        );
+        assert_eq!(contract.get_template(accounts(2), "payroll".into()), None);
+
+        let subscription = contract.create_from_template("payroll".into(), accounts(2));
+        assert_eq!(subscription.source, accounts(1));
+        assert_eq!(subscription.destination, accounts(2));
+        assert_eq!(subscription.flow, 100);
+        assert_eq!(subscription.end_timestamp, Some(60));
+
+        contract.save_template("payroll".into(), 200, None);
+        let subscription = contract.create_from_template("payroll".into(), accounts(3));
+        assert_eq!(subscription.flow, 200);
+        assert_eq!(subscription.end_timestamp, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "template not present")]
+    fn test_create_from_missing_template() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.create_from_template("payroll".into(), accounts(2));
+    }
+
+    #[test]
+    fn test_net_flow_to_destination() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.reserve = 0;
+        contract.set_fee_basis_points(500);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+        assert_eq!(contract.net_flow_to_destination(subscription_index).0, 95);
+
+        testing_env!(context.block_timestamp(20).build());
+        contract.remove_subscription(subscription_index);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 95 * 10);
+        assert_eq!(contract.accumulated_fees().0, 50);
+        assert_eq!(contract.balances.get(&accounts(1)), Some(999_999_000));
+    }
+
+    #[test]
+    fn test_withdraw_fees() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.set_fee_basis_points(500);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+
+        testing_env!(context.block_timestamp(20).build());
+        contract.settle_partial(1);
+        testing_env!(context.block_timestamp(30).build());
+        contract.settle_partial(1);
+        assert_eq!(contract.accumulated_fees().0, 100);
+        assert_eq!(contract.balances.get(&accounts(0)), None);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.withdraw_fees(60.into());
+        assert_eq!(contract.accumulated_fees().0, 40);
+        assert_eq!(contract.balances.get(&accounts(0)), Some(60));
+    }
+
+    #[test]
+    fn test_fees_on_day() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.block_timestamp(SECONDS_PER_DAY - 10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.set_fee_basis_points(500);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+
+        testing_env!(context.block_timestamp(SECONDS_PER_DAY - 5).build());
+        contract.settle_partial(1);
+        testing_env!(context.block_timestamp(SECONDS_PER_DAY - 1).build());
+        contract.settle_partial(1);
+        testing_env!(context.block_timestamp(SECONDS_PER_DAY + 5).build());
+        contract.settle_partial(1);
+
+        assert_eq!(contract.fees_on_day(0).0, 45);
+        assert_eq!(contract.fees_on_day(1).0, 30);
+        assert_eq!(contract.fees_on_day(2).0, 0);
+        assert_eq!(contract.accumulated_fees().0, 75);
+    }
+
+    #[test]
+    #[should_panic(expected = "amount exceeds collected fees")]
+    fn test_withdraw_more_than_collected_fees() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.withdraw_fees(1.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "fee exceeds maximum basis points")]
+    fn test_fee_exceeds_maximum() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.set_fee_basis_points(10_001);
+    }
+
+    #[test]
+    fn test_fee_rounding_mode() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.set_fee_basis_points(25);
+
+        assert_eq!(contract.rounding_mode(), RoundingMode::Floor);
+        assert_eq!(contract.fee(199), 0);
+        assert_eq!(contract.fee(200), 0);
+        assert_eq!(contract.fee(400), 1);
+
+        contract.set_rounding_mode(RoundingMode::HalfUp);
+        assert_eq!(contract.fee(199), 0);
+        assert_eq!(contract.fee(200), 1);
+        assert_eq!(contract.fee(599), 1);
+        assert_eq!(contract.fee(600), 2);
+    }
+
+    #[test]
+    fn test_preview_fee() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.set_fee_basis_points(25);
+
+        assert_eq!(contract.preview_fee(U128(0)), (U128(0), U128(0)));
+        assert_eq!(contract.preview_fee(U128(399)), (U128(0), U128(399)));
+        assert_eq!(contract.preview_fee(U128(400)), (U128(1), U128(399)));
+        assert_eq!(contract.preview_fee(U128(1_000)), (U128(2), U128(998)));
+        assert_eq!(
+            contract.preview_fee(U128(1_000_000)),
+            (U128(2_500), U128(997_500))
+        );
+    }
+
+    #[test]
+    fn test_migrate_from_v1() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut legacy_subscriptions: LookupMap<SubscriptionIndex, SubscriptionV1> =
+            LookupMap::new(StorageKey::Subscriptions);
+        legacy_subscriptions.insert(
+            &1,
+            &SubscriptionV1 {
+                source: accounts(1),
+                destination: accounts(2),
+                flow: 100,
+                timestamp: 10,
+            },
+        );
+        let mut balances = LookupMap::new(StorageKey::Balances);
+        balances.insert(&accounts(1), &1_000_000_000);
+        env::state_write(&PaystreamV1 {
+            wrap_contract: WRAP_CONTRACT.parse().unwrap(),
+            token: FungibleToken::new(StorageKey::FungibleToken),
+            metadata: LazyOption::new(StorageKey::Metadata, None),
+            balances,
+            owner: accounts(0),
+            treasurer: accounts(3),
+            subscriptions: Subscriptions {
+                subscription_index: 1,
+                subscriptions: LookupMap::new(StorageKey::Subscriptions),
+                outputs: LookupMap::new(StorageKey::Outputs),
+                inputs: LookupMap::new(StorageKey::Inputs),
+            },
+            reserve: 60,
+        });
+
+        let contract = Paystream::migrate();
+        assert_eq!(contract.owner(), &accounts(0));
+        assert_eq!(contract.treasurer(), &accounts(3));
+        assert_eq!(contract.reserve, 60);
+        assert_eq!(contract.fee_basis_points(), 0);
+        assert_eq!(contract.balances.get(&accounts(1)), Some(1_000_000_000));
+        assert_eq!(contract.version(), env!("CARGO_PKG_VERSION"));
+        assert_eq!(contract.stats().active_subscriptions, 1);
+        assert_eq!(contract.stats().committed_flow, U128(100));
+        assert_eq!(
+            contract.get_subscription(1),
+            Subscription {
+                source: accounts(1),
+                destination: accounts(2),
+                flow: 100,
+                timestamp: 10,
+                end_timestamp: None,
+                paid_from: 10,
+                created_at: 10,
+                lump_sum: 0,
+                unlock_at: 0,
+                locked_reserve: 6_000,
+                pull: true,
+                total_settled: 0,
+                active: true,
+                cap: None,
+                condition: None,
+                scaled_flow: None,
+                scaled_remainder: 0,
+                notify: None,
+                group_id: None,
+                frozen_offset: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_subscriptions_below_and_at_limit() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(1), WRAP_CONTRACT.parse().unwrap());
+        contract.reserve = 0;
+        contract.update_max_subscriptions_per_account(2);
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.create_subscription(accounts(1), accounts(3), 100);
+        assert_eq!(contract.subscriptions_by_account().len(), 2);
+
+        // Removing a subscription frees a place for another
+        let subscription_index = contract.subscriptions_by_account()[0];
+        contract.remove_subscription(subscription_index);
+        contract.create_subscription(accounts(1), accounts(4), 100);
+        assert_eq!(contract.subscriptions_by_account().len(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "subscription limit reached")]
+    fn test_subscriptions_above_limit_for_source() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(1), WRAP_CONTRACT.parse().unwrap());
+        contract.update_max_subscriptions_per_account(2);
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.create_subscription(accounts(1), accounts(3), 100);
+        contract.create_subscription(accounts(1), accounts(4), 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "subscription limit reached")]
+    fn test_subscriptions_above_limit_for_destination() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(1), WRAP_CONTRACT.parse().unwrap());
+        contract.update_max_subscriptions_per_account(1);
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.balances.insert(&accounts(3), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.create_subscription(accounts(3), accounts(2), 100);
+    }
+
+    #[test]
+    fn test_my_outgoing_and_incoming_subscriptions() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.balances.insert(&accounts(2), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.create_subscription(accounts(2), accounts(3), 50);
+        contract.create_subscription(accounts(2), accounts(1), 50);
+
+        assert_eq!(contract.my_outgoing_subscriptions(), vec![2, 3]);
+        assert_eq!(contract.my_incoming_subscriptions(), vec![1]);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        assert_eq!(contract.my_outgoing_subscriptions(), vec![1]);
+        assert_eq!(contract.my_incoming_subscriptions(), vec![3]);
+    }
+
+    #[test]
+    fn test_outgoing_destinations() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.create_subscription(accounts(1), accounts(3), 50);
+        contract.create_subscription(accounts(1), accounts(2), 200);
+
+        assert_eq!(
+            contract.outgoing_destinations(accounts(1)),
+            vec![(accounts(2), U128(300)), (accounts(3), U128(50))]
+        );
+        assert!(contract.outgoing_destinations(accounts(2)).is_empty());
+    }
+
+    #[test]
+    fn test_trial_subscription() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.reserve = 0;
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        let subscription = contract.create_trial_subscription(accounts(1), accounts(2), 100, 100);
+        assert_eq!(subscription.paid_from, 110);
+        let subscription_index = contract.subscriptions.subscription_index;
+
+        testing_env!(context.block_timestamp(110).build());
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 0);
+        assert_eq!(contract.settle_partial(subscription_index).0, 0);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 0);
+
+        testing_env!(context.block_timestamp(210).build());
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 10_000);
+        contract.remove_subscription(subscription_index);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 10_000);
+    }
+
+    #[test]
+    fn test_settle_partial_after_trial() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.reserve = 0;
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_trial_subscription(accounts(1), accounts(2), 100, 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+        contract.balances.insert(&accounts(1), &5_000);
+
+        testing_env!(context.block_timestamp(210).build());
+        assert_eq!(contract.settle_partial(subscription_index).0, 5_000);
+        assert_eq!(contract.get_subscription(subscription_index).timestamp, 160);
+
+        contract.balances.insert(&accounts(1), &1_000_000);
+        assert_eq!(contract.settle_partial(subscription_index).0, 0);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 10_000);
+        assert_eq!(contract.balances.get(&accounts(1)), Some(995_000));
+    }
+
+    #[test]
+    fn test_subscription_starting_in_future() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.reserve = 0;
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription_starting(accounts(2), 100, 50);
+        let subscription_index = contract.subscriptions.subscription_index;
+
+        testing_env!(context.block_timestamp(40).build());
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 0);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 1_000_000_000);
+        contract.settle_partial(subscription_index);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 0);
+
+        testing_env!(context.block_timestamp(70).build());
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 2_000);
+        contract.settle_partial(subscription_index);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 2_000);
+        assert_eq!(contract.balances.get(&accounts(1)), Some(999_998_000));
+    }
+
+    #[test]
+    #[should_panic(expected = "start must not be in the past")]
+    fn test_subscription_starting_in_past() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription_starting(accounts(2), 100, 5);
+    }
+
+    #[test]
+    fn test_supported_features() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        let features = contract.supported_features();
+        assert!(features.contains(&"pull_settlement".to_string()));
+        assert!(features.contains(&"scheduled_payments".to_string()));
+        assert!(!features.contains(&"multi_token".to_string()));
+    }
+
+    #[test]
+    fn test_supported_features_cover_entry_points() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        let features = contract.supported_features();
+        // The feature each public entry point is advertised under
+        let entry_points = [
+            ("set_settlement_mode", "pull_settlement"),
+            ("create_scheduled_payment", "scheduled_payments"),
+            ("create_trial_subscription", "trial_subscriptions"),
+            ("create_from_template", "templates"),
+            ("request_stream", "stream_requests"),
+            ("create_capped_subscription", "capped_subscriptions"),
+            (
+                "create_conditional_subscription",
+                "conditional_subscriptions",
+            ),
+            ("create_subscription_scaled", "scaled_flow"),
+            ("acknowledge_subscription", "destination_acknowledgment"),
+            ("set_enforce_allowlist", "destination_allowlist"),
+            ("create_subscription_signed", "relayed_create"),
+            ("set_notification_target", "settlement_notifications"),
+            ("create_escrowed_subscription", "escrowed_subscriptions"),
+            ("create_passthrough", "passthrough"),
+            ("create_group", "subscription_groups"),
+            ("create_subscription_starting", "future_start"),
+            ("set_incoming_split", "incoming_splits"),
+            ("clawback_subscription", "clawback"),
+        ];
+        for (entry_point, feature) in entry_points {
+            assert!(
+                features.contains(&feature.to_string()),
+                "{} isn't advertised as {}",
+                entry_point,
+                feature
+            );
+        }
+        assert_eq!(features.len(), entry_points.len());
+    }
+
+    #[test]
+    fn test_checked_arithmetic() {
+        assert_eq!(Yoctos(1).checked_add(Yoctos(2)), Some(Yoctos(3)));
+        assert_eq!(Yoctos(u128::MAX).checked_add(Yoctos(1)), None);
+        assert_eq!(Yoctos(1).checked_sub(Yoctos(2)), None);
+        assert_eq!(Flow(100).checked_over(10), Some(Yoctos(1_000)));
+        assert_eq!(Flow(u128::MAX / 2 + 1).checked_over(2), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "reserve overflows")]
+    fn test_reserve_overflows() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.minimum_balance(u128::MAX);
+    }
+
+    #[test]
+    #[should_panic(expected = "accrual overflows")]
+    fn test_accrual_overflows() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.reserve = 0;
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), u128::MAX / 2 + 1);
+        testing_env!(context.block_timestamp(2).build());
+        contract.settle_partial(contract.subscriptions.subscription_index);
+    }
+
+    #[test]
+    fn test_total_created_and_active() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.create_subscription(accounts(1), accounts(3), 100);
+        assert_eq!(contract.total_created().0, 2);
+        assert_eq!(contract.total_active().0, 2);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.remove_subscription(1);
+        assert_eq!(contract.total_created().0, 2);
+        assert_eq!(contract.total_active().0, 1);
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.remove_subscription(2);
+        assert_eq!(contract.total_created().0, 2);
+        assert_eq!(contract.total_active().0, 0);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.reserve = 0;
+        contract.freeze_account(accounts(4));
+        testing_env!(
+            context.build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        contract.wrap_callback(accounts(1), 1_000_000_000);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.create_subscription(accounts(1), accounts(3), 50);
+        assert_eq!(
+            contract.stats(),
+            ContractStats {
+                active_subscriptions: 2,
+                committed_flow: U128(150),
+                streaming_balance: U128(1_000_000_000),
+                frozen_accounts: 1,
+                timestamp: 10,
+            }
+        );
+
+        testing_env!(context.block_timestamp(20).build());
+        let subscription_index = contract.subscriptions.subscription_index;
+        contract.update_subscription(subscription_index, 200);
+        contract.remove_subscription(subscription_index - 1);
+        assert_eq!(
+            contract.stats(),
+            ContractStats {
+                active_subscriptions: 1,
+                committed_flow: U128(200),
+                streaming_balance: U128(1_000_000_000),
+                frozen_accounts: 1,
+                timestamp: 20,
+            }
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot settle, insolvent source")]
+    fn test_remove_subscription_strict_insolvent() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+        contract.balances.insert(&accounts(1), &500);
+
+        testing_env!(context.block_timestamp(20).build());
+        contract.remove_subscription_strict(subscription_index);
+    }
+
+    #[test]
+    fn test_remove_subscription_strict_solvent() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.reserve = 0;
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+
+        testing_env!(context.block_timestamp(20).build());
+        contract.remove_subscription_strict(subscription_index);
+        assert!(!contract.subscriptions.exists(subscription_index));
+        assert_eq!(contract.claimable_of(accounts(2)).0, 1_000);
+    }
+
+    #[test]
+    fn test_remove_subscription_insolvent() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+        contract.balances.insert(&accounts(1), &500);
+
+        testing_env!(context
+            .block_timestamp(20)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.remove_subscription(subscription_index);
+        assert!(!contract.subscriptions.exists(subscription_index));
+        assert_eq!(contract.claimable_of(accounts(2)).0, 500);
+        assert_eq!(contract.balances.get(&accounts(1)), Some(0));
+    }
+
+    #[test]
+    fn test_remove_subscription_forgets_history() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+        contract.update_subscription(subscription_index, 200);
+        contract.settle_with_nonce(subscription_index, 7);
+
+        testing_env!(context
+            .block_timestamp(20)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.approve_end_time(subscription_index, 100);
+        contract.remove_subscription(subscription_index);
+        assert!(contract.flow_history.get(&subscription_index).is_none());
+        assert!(contract
+            .settlement_nonces
+            .get(&subscription_index)
+            .is_none());
+        assert!(contract
+            .end_time_approvals
+            .get(&subscription_index)
+            .is_none());
+    }
+
+    #[test]
+    fn test_completed_subscription_forgets_nonce() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_capped_subscription(accounts(2), 100, U128(1_000));
+        let subscription_index = contract.subscriptions.subscription_index;
+
+        testing_env!(context.block_timestamp(20).build());
+        contract.settle_with_nonce(subscription_index, 7);
+        assert!(!contract.subscriptions.exists(subscription_index));
+        assert!(contract.flow_history.get(&subscription_index).is_none());
+        assert!(contract
+            .settlement_nonces
+            .get(&subscription_index)
+            .is_none());
+    }
+
+    #[test]
+    fn test_yocto_conversion() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        let one_near = 1_000_000_000_000_000_000_000_000;
+        assert_eq!(contract.to_yocto(U128(5)).0, 5 * one_near);
+        assert_eq!(contract.from_yocto(contract.to_yocto(U128(5))).0, 5);
+        assert_eq!(contract.from_yocto(U128(2 * one_near - 1)).0, 1);
+        assert_eq!(contract.from_yocto(U128(one_near - 1)).0, 0);
+        assert_eq!(
+            contract.flow_from_near_per_day(U128(864)).0,
+            864 * one_near / 86_400
+        );
+        assert_eq!(
+            contract.flow_from_near_per_day(U128(1)).0,
+            11_574_074_074_074_074_074
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "amount overflows")]
+    fn test_to_yocto_overflows() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.to_yocto(U128(u128::MAX));
+    }
+
+    #[test]
+    fn test_role_in_subscription() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+
+        assert_eq!(
+            contract.role_in_subscription(subscription_index, accounts(1)),
+            Some("source".to_string())
+        );
+        assert_eq!(
+            contract.role_in_subscription(subscription_index, accounts(2)),
+            Some("destination".to_string())
+        );
+        assert_eq!(
+            contract.role_in_subscription(subscription_index, accounts(3)),
+            None
+        );
+        assert_eq!(
+            contract.role_in_subscription(subscription_index + 1, accounts(1)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_set_end_time_extended_by_source() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription_until(accounts(1), accounts(2), 100, 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+
+        testing_env!(context.block_timestamp(50).build());
+        let subscription = contract.set_end_time(subscription_index, Some(200));
+        assert_eq!(subscription.end_timestamp, Some(200));
+        assert_eq!(subscription.timestamp, 50);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 4_000);
+
+        let subscription = contract.set_end_time(subscription_index, None);
+        assert_eq!(subscription.end_timestamp, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "destination must approve shortening")]
+    fn test_set_end_time_shortened_without_approval() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription_until(accounts(1), accounts(2), 100, 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+
+        testing_env!(context.block_timestamp(50).build());
+        contract.set_end_time(subscription_index, Some(80));
+    }
+
+    #[test]
+    fn test_set_end_time_shortened_with_approval() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.approve_end_time(subscription_index, 80);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        let subscription = contract.set_end_time(subscription_index, Some(80));
+        assert_eq!(subscription.end_timestamp, Some(80));
+    }
+
+    #[test]
+    #[should_panic(expected = "end must be in the future")]
+    fn test_set_end_time_in_the_past() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+        contract.set_end_time(subscription_index, Some(10));
+    }
+
+    #[test]
+    fn test_sweep_dust() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.balances.insert(&accounts(1), &10);
+        contract.balances.insert(&accounts(3), &20);
+        contract.balances.insert(&accounts(4), &2_000);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let swept = contract.sweep_dust(vec![accounts(1), accounts(3), accounts(4)], U128(1_000));
+        assert_eq!(swept.0, 20);
+        assert_eq!(contract.balances.get(&accounts(0)), Some(20));
+        assert_eq!(contract.balances.get(&accounts(1)), Some(10));
+        assert_eq!(contract.balances.get(&accounts(3)), None);
+        assert_eq!(contract.balances.get(&accounts(4)), Some(2_000));
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"paystream","version":"1.0.0","event":"sweep_dust","data":[{"account_id":"danny","amount":"20"}]}"#
+            ]
+        );
+    }
+
+    #[test]
+    fn test_subscription_error_json() {
+        assert_eq!(
+            SubscriptionError::NotPresent(1).to_json_string(),
+            r#"{"error":"NotPresent","data":1}"#
+        );
+        assert_eq!(
+            SubscriptionError::InvalidFlow(100).to_json_string(),
+            r#"{"error":"InvalidFlow","data":100}"#
+        );
+        assert_eq!(
+            SubscriptionError::InternalError.to_json_string(),
+            r#"{"error":"InternalError"}"#
+        );
+        assert_eq!(
+            near_sdk::serde_json::from_str::<SubscriptionError>(
+                r#"{"error":"NotPresent","data":1}"#
+            )
+            .unwrap(),
+            SubscriptionError::NotPresent(1)
+        );
+    }
+
+    #[test]
+    fn test_try_methods_return_errors() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        assert_eq!(
+            contract.try_get_subscription(1),
+            Err(SubscriptionError::NotPresent(1))
+        );
+        assert_eq!(
+            contract.try_remove_subscription(1),
+            Err(SubscriptionError::NotPresent(1))
+        );
+        assert_eq!(
+            contract.try_update_subscription(1, 200),
+            Err(SubscriptionError::NotPresent(1))
+        );
+
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        assert_eq!(
+            contract.try_update_subscription(1, 100),
+            Err(SubscriptionError::InvalidFlow(100))
+        );
+        assert_eq!(contract.try_update_subscription(1, 200).unwrap().flow, 200);
+        assert_eq!(
+            contract.try_get_subscription(1),
+            Ok(contract.get_subscription(1))
+        );
+    }
+
+    /// Balance calculated by visiting every subscription of the account
+    fn iterative_balance(contract: &Paystream, account_id: AccountId) -> u128 {
+        let timestamp = env::block_timestamp();
+        let mut balance = contract.balances.get(&account_id).unwrap_or_default();
+        for subscription_index in contract
+            .subscriptions
+            .inputs
+            .get(&account_id)
+            .unwrap_or_default()
+        {
+            let subscription = contract.subscriptions.get(subscription_index).unwrap();
+            balance = balance.saturating_add(subscription.accrued(timestamp));
+        }
+        for subscription_index in contract
+            .subscriptions
+            .outputs
+            .get(&account_id)
+            .unwrap_or_default()
+        {
+            let subscription = contract.subscriptions.get(subscription_index).unwrap();
+            balance = balance.saturating_sub(subscription.accrued(timestamp));
+        }
+        balance
+    }
+
+    #[test]
+    fn test_aggregate_balance_matches_iterative() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.reserve = 0;
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.balances.insert(&accounts(2), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let updated = contract.subscriptions.subscription_index;
+        contract.create_subscription(accounts(1), accounts(3), 50);
+        let removed = contract.subscriptions.subscription_index;
+        contract.create_subscription_until(accounts(1), accounts(2), 30, 100);
+        let bounded = contract.subscriptions.subscription_index;
+        contract.create_trial_subscription(accounts(1), accounts(3), 20, 40);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.create_subscription(accounts(2), accounts(3), 70);
+
+        let assert_balances = |contract: &Paystream| {
+            for account_id in [accounts(1), accounts(2), accounts(3)] {
+                assert_eq!(
+                    contract.ft_balance_of(account_id.clone()).0,
+                    iterative_balance(contract, account_id)
+                );
+            }
+        };
+
+        for timestamp in [10, 30, 60, 120] {
+            testing_env!(context.block_timestamp(timestamp).build());
+            assert_balances(&contract);
+        }
+
+        testing_env!(context
+            .block_timestamp(150)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.update_subscription(updated, 200);
+        contract.remove_subscription(removed);
+        contract.set_end_time(bounded, Some(300));
+        assert_balances(&contract);
+
+        testing_env!(context
+            .block_timestamp(200)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.settle_partial(updated);
+        contract.transfer_incoming(updated, accounts(4));
+        assert_balances(&contract);
+
+        for timestamp in [250, 400] {
+            testing_env!(context.block_timestamp(timestamp).build());
+            assert_balances(&contract);
+            assert_eq!(
+                contract.ft_balance_of(accounts(4)).0,
+                iterative_balance(&contract, accounts(4))
+            );
+        }
+    }
+
+    #[test]
+    fn test_subscription_age() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+
+        testing_env!(context.block_timestamp(30).build());
+        contract.settle_partial(subscription_index);
+        testing_env!(context.block_timestamp(50).build());
+        let subscription = contract.update_subscription(subscription_index, 200);
+        assert_eq!(subscription.timestamp, 50);
+        assert_eq!(subscription.created_at, 10);
+        assert_eq!(contract.subscription_age(subscription_index).0, 40);
+    }
+
+    #[test]
+    fn test_subscription_token_metadata() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+
+        let metadata =
+            contract.subscription_token_metadata(contract.subscriptions.subscription_index);
+        let registered = contract.ft_metadata();
+        assert_eq!(metadata.symbol, registered.symbol);
+        assert_eq!(metadata.decimals, registered.decimals);
+        assert_eq!(metadata.symbol, STREAM_SYMBOL);
+    }
+
+    #[test]
+    fn test_admin_settle_batch() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.reserve = 0;
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.create_subscription(accounts(1), accounts(3), 100);
+        contract.create_subscription(accounts(1), accounts(4), 100);
+        contract.remove_subscription(2);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(20)
+            .build());
+        assert_eq!(contract.admin_settle_batch(1, 2), 2);
+        assert_eq!(contract.admin_settle_batch(3, 2), 1);
+        assert_eq!(contract.admin_settle_batch(4, 2), 0);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 1_000);
+        assert_eq!(contract.claimable_of(accounts(3)).0, 0);
+        assert_eq!(contract.claimable_of(accounts(4)).0, 1_000);
+        assert_eq!(contract.subscriptions.get(1).unwrap().timestamp, 20);
+        assert_eq!(contract.subscriptions.get(3).unwrap().timestamp, 20);
+    }
+
+    #[test]
+    fn test_admin_settle_batch_from_zero() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.reserve = 0;
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.create_subscription(accounts(1), accounts(3), 100);
+        contract.create_subscription(accounts(1), accounts(4), 100);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(20)
+            .build());
+        assert_eq!(contract.admin_settle_batch(0, 2), 2);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 1_000);
+        assert_eq!(contract.claimable_of(accounts(3)).0, 0);
+
+        testing_env!(context.block_timestamp(30).build());
+        assert_eq!(contract.admin_settle_batch(2, 2), 2);
+        assert_eq!(contract.admin_settle_batch(4, 2), 0);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 1_000);
+        assert_eq!(contract.claimable_of(accounts(3)).0, 2_000);
+        assert_eq!(contract.claimable_of(accounts(4)).0, 2_000);
+    }
+
+    #[test]
+    fn test_scheduled_payment_before_unlock() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_scheduled_payment(accounts(2), 5_000.into(), 100);
+
+        testing_env!(context.block_timestamp(99).build());
+        assert_eq!(contract.current_balance(accounts(2)).0, 0);
+        assert_eq!(contract.current_balance(accounts(1)).0, 999_995_000);
+        assert_eq!(contract.escrow_balance(1).0, 5_000);
+        assert_eq!(contract.settle_partial(1).0, 0);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 0);
+    }
+
+    #[test]
+    fn test_scheduled_payment_paid_from_escrow() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_scheduled_payment(accounts(2), 5_000.into(), 100);
+        contract.balances.insert(&accounts(1), &0);
+
+        testing_env!(context.block_timestamp(100).build());
+        assert_eq!(contract.current_balance(accounts(2)).0, 5_000);
+        assert_eq!(contract.settle_partial(1).0, 0);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 5_000);
+        assert_eq!(contract.balances.get(&accounts(1)), Some(0));
+    }
+
+    #[test]
+    fn test_scheduled_payment_refunded_on_removal() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_scheduled_payment(accounts(2), 5_000.into(), 100);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .block_timestamp(50)
+            .build());
+        contract.remove_subscription(1);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 0);
+        assert_eq!(contract.escrow_balance(1).0, 0);
+        assert_eq!(contract.balances.get(&accounts(1)), Some(1_000_000_000));
+    }
+
+    #[test]
+    #[should_panic(expected = "create cooldown has not elapsed")]
+    fn test_scheduled_payment_within_cooldown() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.update_create_cooldown(1_000);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_scheduled_payment(accounts(2), 5_000.into(), 100);
+        contract.create_scheduled_payment(accounts(3), 5_000.into(), 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "subscription limit reached")]
+    fn test_scheduled_payment_beyond_limit() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.update_max_subscriptions_per_account(1);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_scheduled_payment(accounts(2), 5_000.into(), 100);
+        contract.create_scheduled_payment(accounts(3), 5_000.into(), 100);
+    }
+
+    #[test]
+    fn test_scheduled_payment_awaits_acknowledgment() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.set_acknowledgment_required(true);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_scheduled_payment(accounts(2), 5_000.into(), 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+
+        testing_env!(context.block_timestamp(150).build());
+        assert_eq!(contract.pending_settlement(subscription_index).0, 0);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.acknowledge_subscription(subscription_index);
+        assert_eq!(contract.pending_settlement(subscription_index).0, 5_000);
+    }
+
+    #[test]
+    fn test_scheduled_payment_after_unlock() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_scheduled_payment(accounts(2), 5_000.into(), 100);
+
+        testing_env!(context.block_timestamp(150).build());
+        assert_eq!(contract.current_balance(accounts(2)).0, 5_000);
+        assert_eq!(
+            contract.current_balance(accounts(1)).0,
+            1_000_000_000 - 5_000
+        );
+        assert_eq!(contract.settle_partial(1).0, 0);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 5_000);
+
+        // The lump sum is only paid once
+        testing_env!(context.block_timestamp(200).build());
+        assert_eq!(contract.settle_partial(1).0, 0);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 5_000);
+    }
+
+    #[test]
+    fn test_reserve_utilization() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.reserve = 1_000;
+        contract.balances.insert(&accounts(1), &1_000_000);
+        assert_eq!(contract.reserve_utilization(accounts(1)), 0);
+
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        assert_eq!(contract.reserve_utilization(accounts(1)), 1_000);
+        contract.create_subscription(accounts(1), accounts(3), 150);
+        assert_eq!(contract.reserve_utilization(accounts(1)), 2_500);
+
+        contract.balances.insert(&accounts(1), &200_000);
+        assert_eq!(contract.reserve_utilization(accounts(1)), 10_000);
+    }
+
+    #[test]
+    fn test_available_balance_after_removal() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.create_subscription(accounts(1), accounts(3), 200);
+        let reserve = contract.reserve as u128;
+        assert_eq!(contract.get_subscription(1).locked_reserve, 100 * reserve);
+        assert_eq!(
+            contract.available_balance(accounts(1)).0,
+            1_000_000_000 - 300 * reserve
+        );
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.remove_subscription(2);
+        assert_eq!(
+            contract.available_balance(accounts(1)).0,
+            1_000_000_000 - 100 * reserve
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "sufficient reserve is required")]
+    fn test_reserve_already_locked() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        let reserve = contract.reserve as u128;
+        contract.balances.insert(&accounts(1), &(150 * reserve + 1));
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.create_subscription(accounts(1), accounts(3), 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "balance is locked by subscription reserves")]
+    fn test_locked_reserve_cannot_be_unwrapped() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.unwrap_near(1_000_000_000);
+    }
+
+    #[test]
+    fn test_settle_twice_in_same_block() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+
+        // Nothing has elapsed since creation
+        assert_eq!(contract.settle_partial(1).0, 0);
+        assert_eq!(contract.claimable.get(&accounts(2)), None);
+
+        testing_env!(context.block_timestamp(20).build());
+        contract.settle_partial(1);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 1_000);
+        contract.settle_partial(1);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 1_000);
+        assert_eq!(
+            contract.balances.get(&accounts(1)),
+            Some(1_000_000_000 - 1_000)
+        );
+    }
+
+    #[test]
+    fn test_incoming_above() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.balances.insert(&accounts(3), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 50);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.create_subscription(accounts(3), accounts(2), 200);
+        contract.create_subscription(accounts(3), accounts(4), 300);
+
+        assert_eq!(contract.incoming_above(accounts(2), 100), vec![2, 3]);
+        assert_eq!(contract.incoming_above(accounts(2), 201), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn test_self_transfer_is_noop() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.set_fee_basis_points(500);
+        contract.balances.insert(&accounts(1), &1_000);
+
+        assert_eq!(
+            contract.try_transfer(accounts(1), accounts(1), 1_000, true),
+            Ok(())
+        );
+        assert_eq!(contract.balances.get(&accounts(1)), Some(1_000));
+        assert_eq!(contract.claimable.get(&accounts(1)), None);
+        assert_eq!(contract.accumulated_fees().0, 0);
+    }
+
+    #[test]
+    fn test_subscription_graph() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.balances.insert(&accounts(2), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.create_subscription(accounts(2), accounts(3), 200);
+        contract.create_subscription(accounts(2), accounts(4), 300);
+
+        assert_eq!(
+            contract.subscription_graph(accounts(2)),
+            SubscriptionGraph {
+                incoming: vec![(1, accounts(1), U128(100))],
+                outgoing: vec![(2, accounts(3), U128(200)), (3, accounts(4), U128(300))],
+            }
+        );
+    }
+
+    fn verify_wrap_with_decimals(
+        context: &mut VMContextBuilder,
+        contract: &mut Paystream,
+        decimals: u8,
+    ) {
+        let metadata = FungibleTokenMetadata {
+            spec: FT_METADATA_SPEC.into(),
+            name: "Wrapped".into(),
+            symbol: "WRAP".into(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals,
+        };
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(
+                near_sdk::serde_json::to_vec(&metadata).unwrap()
+            )],
+        );
+        contract.verify_wrap_callback(accounts(1), 1_000);
+    }
+
+    #[test]
+    fn test_token_decimals() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        assert_eq!(contract.token_decimals(env::current_account_id()), DECIMALS);
+
+        verify_wrap_with_decimals(&mut context, &mut contract, 30);
+        assert_eq!(contract.token_decimals(WRAP_CONTRACT.parse().unwrap()), 30);
+        assert_eq!(contract.wrap_decimals(), None);
+
+        verify_wrap_with_decimals(&mut context, &mut contract, 6);
+        assert_eq!(contract.token_decimals(WRAP_CONTRACT.parse().unwrap()), 6);
+        assert_eq!(contract.wrap_decimals(), Some(6));
+
+        verify_wrap_with_decimals(&mut context, &mut contract, DECIMALS);
+        assert_eq!(
+            contract.token_decimals(WRAP_CONTRACT.parse().unwrap()),
+            DECIMALS
+        );
+        assert_eq!(contract.wrap_decimals(), Some(DECIMALS));
+    }
+
+    #[test]
+    fn test_six_decimal_token_stream() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        verify_wrap_with_decimals(&mut context, &mut contract, 6);
+
+        // One whole token of six decimals is credited as one sNEAR of 24
+        testing_env!(context
+            .predecessor_account_id(WRAP_CONTRACT.parse().unwrap())
+            .build());
+        contract.ft_on_transfer(accounts(1), 1_000_000.into(), "".into());
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 10u128.pow(24));
+        assert!(solvent_holding(&mut context, &contract, 1_000_000));
+        assert!(!solvent_holding(&mut context, &contract, 999_999));
+
+        // A unit of the token a second
+        let flow = 10u128.pow(18);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.create_subscription(accounts(1), accounts(2), flow);
+        testing_env!(context.block_timestamp(20).build());
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 10 * flow);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.settle_partial(contract.subscriptions.subscription_index);
+        contract.claim();
+        contract.unwrap_near(10 * flow);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 0);
+        assert_eq!(contract.streaming_balance, 10u128.pow(24) - 10 * flow);
+    }
+
+    #[test]
+    #[should_panic(expected = "amount must be whole units of the wrap contract's token")]
+    fn test_unwrap_part_of_a_token_unit() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        verify_wrap_with_decimals(&mut context, &mut contract, 6);
+        contract.balances.insert(&accounts(1), &10u128.pow(24));
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.unwrap_near(10u128.pow(18) / 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "wrap contract not verified")]
+    fn test_transfer_in_before_verification() {
+        let context = get_context(WRAP_CONTRACT.parse().unwrap());
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.ft_on_transfer(accounts(1), 1_000_000.into(), "".into());
+    }
+
+    #[test]
+    #[should_panic(expected = "wrap contract doesn't wrap NEAR")]
+    fn test_wrap_near_with_six_decimal_token() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        verify_wrap_with_decimals(&mut context, &mut contract, 6);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1_000)
+            .build());
+        contract.wrap_near();
+    }
+
+    #[test]
+    #[should_panic(expected = "token not registered")]
+    fn test_token_decimals_unregistered() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.token_decimals(accounts(3));
+    }
+
+    #[test]
+    fn test_set_metadata() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.set_metadata("Stream NEAR".into(), "SNEAR".into(), None, None, None);
+
+        let metadata = contract.ft_metadata();
+        assert_eq!(metadata.name, "Stream NEAR");
+        assert_eq!(metadata.symbol, "SNEAR");
+        assert_eq!(metadata.icon, None);
+        assert_eq!(metadata.decimals, DECIMALS);
+    }
+
+    #[test]
+    #[should_panic(expected = "Permission required")]
+    fn test_set_metadata_not_owner() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.set_metadata("Stream NEAR".into(), "SNEAR".into(), None, None, None);
+    }
+
+    #[test]
+    fn test_outgoing_flow_distribution() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        assert_eq!(
+            contract.outgoing_flow_distribution(accounts(1)),
+            vec![(accounts(2), 10_000)]
+        );
+
+        contract.create_subscription(accounts(1), accounts(3), 200);
+        contract.create_subscription(accounts(1), accounts(4), 400);
+        let distribution = contract.outgoing_flow_distribution(accounts(1));
+        assert_eq!(
+            distribution,
+            vec![
+                (accounts(2), 1_428),
+                (accounts(3), 2_857),
+                (accounts(4), 5_715)
+            ]
+        );
+        assert_eq!(
+            distribution.iter().map(|(_, share)| share).sum::<u32>(),
+            10_000
+        );
+        assert!(contract.outgoing_flow_distribution(accounts(2)).is_empty());
+    }
+
+    #[test]
+    fn test_allowlist_not_enforced() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.add_allowed_destination(accounts(2));
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.create_subscription(accounts(1), accounts(3), 100);
+        assert_eq!(contract.subscriptions_by_account().len(), 2);
+    }
+
+    #[test]
+    fn test_allowlist_enforced_allowed_destination() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.set_enforce_allowlist(true);
+        contract.add_allowed_destination(accounts(2));
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        assert!(contract.is_allowed_destination(accounts(1), accounts(2)));
+        assert!(!contract.is_allowed_destination(accounts(1), accounts(3)));
+
+        contract.set_enforce_allowlist(false);
+        contract.create_subscription(accounts(1), accounts(3), 100);
+        assert_eq!(contract.subscriptions_by_account().len(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "destination not allowed")]
+    fn test_allowlist_enforced_disallowed_destination() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.set_enforce_allowlist(true);
+        contract.add_allowed_destination(accounts(2));
+        contract.remove_allowed_destination(accounts(2));
+        contract.create_subscription(accounts(1), accounts(2), 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "destination not allowed")]
+    fn test_allowlist_enforced_on_transfer_incoming() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.set_enforce_allowlist(true);
+        contract.add_allowed_destination(accounts(2));
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.transfer_incoming(contract.subscriptions.subscription_index, accounts(3));
+    }
+
+    #[test]
+    fn test_peek_next_index() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+
+        let next_index = contract.peek_next_index();
+        contract.create_subscription(accounts(1), accounts(3), 100);
+        assert_eq!(next_index.0, 2);
+        assert_eq!(next_index.0, contract.subscriptions.subscription_index);
+    }
+
+    #[test]
+    #[should_panic(expected = "index space exhausted")]
+    fn test_index_space_exhausted() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.subscriptions.subscription_index = u64::MAX - 1;
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        assert_eq!(contract.subscriptions.subscription_index, u64::MAX);
+
+        // Rather than wrapping around onto an existing index
+        contract.create_subscription(accounts(1), accounts(3), 100);
+    }
+
+    #[test]
+    fn test_annualized_incoming() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.create_subscription(accounts(1), accounts(2), 50);
+
+        assert_eq!(
+            contract.annualized_incoming(accounts(2)).0,
+            150 * 31_536_000
+        );
+        assert_eq!(contract.annualized_incoming(accounts(3)).0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "reserve lock period has not elapsed")]
+    fn test_source_removes_within_reserve_lock() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+
+        testing_env!(context
+            .block_timestamp(10 + contract.reserve / 2 - 1)
+            .build());
+        contract.remove_subscription(1);
+    }
+
+    #[test]
+    fn test_removal_after_reserve_lock() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.create_subscription(accounts(1), accounts(3), 100);
+
+        // The destination can always exit
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.remove_subscription(2);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(10 + contract.reserve / 2)
+            .build());
+        contract.remove_subscription(1);
+        assert!(contract.subscriptions_by_account().is_empty());
+    }
+
+    #[test]
+    fn test_storage_usage_of() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        assert_eq!(contract.storage_usage_of(accounts(1)).0, 0);
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        let with_balance = contract.storage_usage_of(accounts(1)).0;
+        assert!(with_balance > 0);
+
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let with_subscription = contract.storage_usage_of(accounts(1)).0;
+        assert!(with_subscription > with_balance);
+        contract.create_subscription(accounts(1), accounts(3), 100);
+        assert!(contract.storage_usage_of(accounts(1)).0 > with_subscription);
+    }
+
+    #[test]
+    fn test_cancel_with_counterparty() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.reserve = 0;
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.balances.insert(&accounts(2), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.create_subscription(accounts(1), accounts(3), 100);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.create_subscription(accounts(2), accounts(1), 50);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(20)
+            .build());
+        assert_eq!(contract.cancel_with_counterparty(accounts(2), 10), 2);
+        assert_eq!(contract.subscriptions_by_account(), vec![2]);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 1_000);
+        assert_eq!(contract.claimable_of(accounts(1)).0, 500);
+        assert_eq!(contract.cancel_with_counterparty(accounts(2), 10), 0);
+    }
+
+    #[test]
+    fn test_update_subscription_with_topup() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        let reserve = contract.reserve as u128;
+        contract.balances.insert(&accounts(1), &(100 * reserve + 1));
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.wrap_decimals = Some(DECIMALS);
+
+        testing_env!(context
+            .predecessor_account_id(WRAP_CONTRACT.parse().unwrap())
+            .build());
+        contract.ft_on_transfer(
+            accounts(1),
+            (100 * reserve).into(),
+            json!({ "subscription_index": 1, "new_flow": "200" }).to_string(),
+        );
+        assert_eq!(contract.get_subscription(1).flow, 200);
+        assert_eq!(contract.balances.get(&accounts(1)), Some(200 * reserve + 1));
+        assert_eq!(contract.streaming_balance, 100 * reserve);
+        assert!(solvent_holding(&mut context, &contract, 100 * reserve));
+    }
+
+    #[test]
+    #[should_panic(expected = "sufficient reserve is required")]
+    fn test_update_subscription_without_topup() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        let reserve = contract.reserve as u128;
+        contract.balances.insert(&accounts(1), &(100 * reserve + 1));
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.wrap_decimals = Some(DECIMALS);
+
+        testing_env!(context
+            .predecessor_account_id(WRAP_CONTRACT.parse().unwrap())
+            .build());
+        contract.ft_on_transfer(
+            accounts(1),
+            1.into(),
+            json!({ "subscription_index": 1, "new_flow": "200" }).to_string(),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "caller must be source")]
+    fn test_update_subscription_with_topup_by_destination() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.wrap_decimals = Some(DECIMALS);
+
+        testing_env!(context
+            .predecessor_account_id(WRAP_CONTRACT.parse().unwrap())
+            .build());
+        contract.ft_on_transfer(
+            accounts(2),
+            1_000_000_000.into(),
+            json!({ "subscription_index": 1, "new_flow": "200" }).to_string(),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "only the wrap contract's token is accepted")]
+    fn test_topup_of_other_token() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.ft_on_transfer(
+            accounts(1),
+            1_000_000_000.into(),
+            json!({ "subscription_index": 1, "new_flow": "200" }).to_string(),
+        );
+    }
+
+    #[test]
+    fn test_push_settlement_mode() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .block_timestamp(20)
+            .build());
+        assert!(!contract.set_settlement_mode(1, false).pull);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 1_000);
+        assert_eq!(contract.balances.get(&accounts(2)), None);
+
+        testing_env!(context.block_timestamp(30).build());
+        contract.settle_partial(1);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 1_000);
+        assert_eq!(contract.balances.get(&accounts(2)), Some(1_000));
+    }
+
+    #[test]
+    fn test_pull_settlement_mode() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.set_settlement_mode(1, false);
+        assert!(contract.set_settlement_mode(1, true).pull);
+
+        testing_env!(context.block_timestamp(20).build());
+        contract.settle_partial(1);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 1_000);
+        assert_eq!(contract.balances.get(&accounts(2)), None);
+    }
+
+    #[test]
+    fn test_incoming_by_pending() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.create_subscription(accounts(1), accounts(2), 300);
+        contract.create_subscription(accounts(1), accounts(2), 200);
+
+        testing_env!(context.block_timestamp(20).build());
+        assert_eq!(contract.pending_settlement(2).0, 3_000);
+        assert_eq!(
+            contract.incoming_by_pending(accounts(2), 2),
+            vec![(2, U128(3_000)), (3, U128(2_000))]
+        );
+        assert_eq!(contract.incoming_by_pending(accounts(2), 10).len(), 3);
+    }
+
+    #[test]
+    fn test_incoming_flow_at_cap() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.set_max_incoming_flow(Some(300));
+        assert_eq!(contract.max_incoming_flow(accounts(2)), Some(300));
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.update_subscription(2, 200);
+        assert_eq!(contract.stats().committed_flow, U128(300));
+    }
+
+    #[test]
+    #[should_panic(expected = "destination inflow cap exceeded")]
+    fn test_create_above_incoming_flow_cap() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.set_max_incoming_flow(Some(300));
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 200);
+        contract.create_subscription(accounts(1), accounts(2), 101);
+    }
+
+    #[test]
+    #[should_panic(expected = "destination inflow cap exceeded")]
+    fn test_update_above_incoming_flow_cap() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.set_max_incoming_flow(Some(300));
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 200);
+        contract.update_subscription(1, 301);
+    }
+
+    #[test]
+    fn test_get_subscriptions() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.create_subscription(accounts(1), accounts(3), 200);
+
+        let subscriptions = contract.get_subscriptions(vec![2, 5, 1]);
+        assert_eq!(subscriptions.len(), 3);
+        assert_eq!(subscriptions[0].as_ref().unwrap().destination, accounts(3));
+        assert_eq!(subscriptions[1], None);
+        assert_eq!(subscriptions[2].as_ref().unwrap().destination, accounts(2));
+    }
+
+    #[test]
+    fn test_average_flow() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+
+        testing_env!(context.block_timestamp(40).build());
+        contract.update_subscription(1, 400);
+
+        // 30 seconds at 100 then 10 seconds at 400
+        assert_eq!(contract.average_flow(1, 10, 50).0, 175);
+        // Nothing flowed before creation
+        assert_eq!(contract.average_flow(1, 0, 20).0, 50);
+        assert_eq!(contract.average_flow(1, 40, 60).0, 400);
+    }
+
+    #[test]
+    fn test_request_accepted() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        let request_id = contract.request_stream(accounts(1), 100, Some(100));
+        assert_eq!(contract.requests_for_source(accounts(1)), vec![request_id]);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        let subscription = contract.accept_request(request_id);
+        assert_eq!(subscription.destination, accounts(2));
+        assert_eq!(subscription.flow, 100);
+        assert_eq!(subscription.end_timestamp, Some(100));
+        assert_eq!(contract.get_request(request_id), None);
+        assert!(contract.requests_for_source(accounts(1)).is_empty());
+    }
+
+    #[test]
+    fn test_request_rejected() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        let request_id = contract.request_stream(accounts(1), 100, None);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.reject_request(request_id);
+        assert_eq!(contract.get_request(request_id), None);
+        assert!(contract.requests_for_source(accounts(1)).is_empty());
+        assert_eq!(contract.stats().active_subscriptions, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "too many pending requests")]
+    fn test_request_beyond_pending_limit() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        for payee in 0..MAX_PENDING_REQUESTS {
+            testing_env!(context
+                .clone()
+                .predecessor_account_id(format!("payee{}.near", payee).parse().unwrap())
+                .build());
+            contract.request_stream(accounts(1), 100, None);
+        }
+        testing_env!(context.build());
+        contract.request_stream(accounts(1), 100, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "too many pending requests from destination")]
+    fn test_request_beyond_destination_pending_limit() {
+        let mut context = get_context(accounts(3));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.request_stream(accounts(1), 100, None);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        for _ in 0..MAX_PENDING_REQUESTS_PER_DESTINATION {
+            contract.request_stream(accounts(1), 100, None);
+        }
+        contract.request_stream(accounts(1), 100, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "caller must be source")]
+    fn test_request_accepted_by_destination() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        let request_id = contract.request_stream(accounts(1), 100, None);
+        contract.accept_request(request_id);
+    }
+
+    #[test]
+    fn test_lifetime_paid() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+
+        testing_env!(context.block_timestamp(20).build());
+        contract.settle_partial(1);
+        testing_env!(context.block_timestamp(30).build());
+        contract.update_subscription(1, 200);
+        testing_env!(context.block_timestamp(40).build());
+        contract.set_end_time(1, None);
+        testing_env!(context.block_timestamp(50).build());
+        contract.settle_partial(1);
+
+        assert_eq!(contract.lifetime_paid(1).0, 1_000 + 1_000 + 2_000 + 2_000);
+        assert_eq!(
+            contract.lifetime_paid(1).0,
+            contract.claimable_of(accounts(2)).0
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "account is frozen")]
+    fn test_recover_account_to_frozen_account() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.freeze_account(accounts(5));
+        contract.recover_account(accounts(1), accounts(5));
+    }
+
+    #[test]
+    fn test_recover_account() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.balances.insert(&accounts(2), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.create_subscription(accounts(2), accounts(1), 50);
+        contract.claimable.insert(&accounts(1), &300);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(20)
+            .build());
+        contract.recover_account(accounts(1), accounts(5));
+        assert_eq!(contract.get_subscription(1).source, accounts(5));
+        assert_eq!(contract.get_subscription(2).destination, accounts(5));
+        assert_eq!(
+            contract.subscriptions.outputs.get(&accounts(5)),
+            Some(vec![1])
+        );
+        assert_eq!(
+            contract.subscriptions.inputs.get(&accounts(5)),
+            Some(vec![2])
+        );
+        assert_eq!(contract.subscriptions.outputs.get(&accounts(1)), None);
+        assert_eq!(contract.subscriptions.inputs.get(&accounts(1)), None);
+        assert_eq!(contract.balances.get(&accounts(1)), None);
+        assert_eq!(contract.claimable_of(accounts(5)).0, 300);
+
+        // Accrual carries over to the new account
+        assert_eq!(
+            contract.ft_balance_of(accounts(5)).0,
+            1_000_000_000 - 1_000 + 500
+        );
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 0);
+        assert_eq!(
+            contract.ft_balance_of(accounts(5)).0,
+            iterative_balance(&contract, accounts(5))
+        );
+    }
+
+    #[test]
+    fn test_recover_account_moves_clawback_and_group() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.balances.insert(&accounts(2), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(3), 100);
+        let group_id = contract.create_group();
+        contract.add_to_group(group_id, 1);
+        contract.set_max_incoming_flow(Some(1_000));
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.create_subscription(accounts(2), accounts(1), 50);
+
+        testing_env!(context.block_timestamp(20).build());
+        contract.settle_partial(2);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.recover_account(accounts(1), accounts(5));
+        assert_eq!(contract.group_owners.get(&group_id), Some(accounts(5)));
+        assert_eq!(contract.max_incoming_flow(accounts(5)), Some(1_000));
+        assert_eq!(contract.max_incoming_flow(accounts(1)), None);
+        assert_eq!(contract.claimable_of(accounts(5)).0, 500);
+
+        // The clawback reverses what was credited to the old account from the new one
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.clawback_subscription(2);
+        assert_eq!(contract.claimable_of(accounts(5)).0, 0);
+        assert_eq!(contract.balances.get(&accounts(2)), Some(1_000_000_000));
+    }
+
+    #[test]
+    fn test_destination_registered_on_create() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        assert!(!contract.token.accounts.contains_key(&accounts(2)));
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        assert!(contract.token.accounts.contains_key(&accounts(2)));
+
+        // Registering twice is avoided
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        testing_env!(context.block_timestamp(20).build());
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 2_000);
+    }
+
+    #[test]
+    fn test_new_destination_registered_on_transfer() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.transfer_incoming(1, accounts(3));
+        assert!(contract.token.accounts.contains_key(&accounts(3)));
+    }
+
+    #[test]
+    fn test_deployed_at() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(42).build());
+        let contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        testing_env!(context.block_timestamp(100).build());
+        assert_eq!(contract.deployed_at().0, 42);
+    }
+
+    #[test]
+    fn test_flow_units() {
+        assert_eq!(FlowUnit::PerSecond.to_flow(100), 100);
+        assert_eq!(FlowUnit::PerHour.to_flow(360_000), 100);
+        assert_eq!(FlowUnit::PerDay.to_flow(8_640_000), 100);
+        assert_eq!(FlowUnit::PerMonth.to_flow(259_200_000), 100);
+        assert_eq!(FlowUnit::PerHour.to_flow(3_599), 0);
+    }
+
+    #[test]
+    fn test_create_subscription_with_unit() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        let subscription =
+            contract.create_subscription_with_unit(accounts(2), 8_640_000.into(), FlowUnit::PerDay);
+        assert_eq!(subscription.flow, 100);
+    }
+
+    #[test]
+    fn test_reserve_seconds_for_flow() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.update_reserve(60);
+        contract.update_reserve_tiers(vec![(1_000, 600), (100, 120)]);
+
+        assert_eq!(contract.reserve_seconds_for_flow(99).0, 60);
+        assert_eq!(contract.reserve_seconds_for_flow(100).0, 120);
+        assert_eq!(contract.reserve_seconds_for_flow(999).0, 120);
+        assert_eq!(contract.reserve_seconds_for_flow(1_000).0, 600);
+        assert_eq!(contract.minimum_balance(1_000), 600_000);
+    }
+
+    #[test]
+    fn test_no_accrual_until_acknowledged() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.set_acknowledgment_required(true);
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+
+        testing_env!(context.block_timestamp(30).build());
+        assert_eq!(contract.current_balance(accounts(2)).0, 0);
+        contract.settle_partial(subscription_index);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 0);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.acknowledge_subscription(subscription_index);
+        testing_env!(context.block_timestamp(40).build());
+        assert_eq!(contract.current_balance(accounts(2)).0, 1_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "caller must be destination")]
+    fn test_acknowledge_by_source() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.set_acknowledgment_required(true);
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.acknowledge_subscription(contract.subscriptions.subscription_index);
+    }
+
+    #[test]
+    fn test_admin_set_timestamp() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let subscription_index = contract.subscriptions.subscription_index;
 
-        self.balances.insert(&source, &new_balance_of_source);
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(50)
+            .build());
+        contract.admin_set_timestamp(subscription_index, 5);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 4_000);
+        assert_eq!(contract.current_balance(accounts(2)).0, 500);
+        let subscription = contract.get_subscription(subscription_index);
+        assert_eq!(subscription.created_at, 5);
+        assert_eq!(subscription.timestamp, 45);
+        assert!(get_logs()[0].contains("\"event\":\"timestamp_corrected\""));
+    }
 
-        match self.balances.get(&destination) {
-            Some(current_balance) => self
-                .balances
-                .insert(&destination, &current_balance.saturating_add(amount)),
-            None => self.balances.insert(&destination, &amount),
-        };
+    #[test]
+    fn test_admin_set_timestamp_of_scheduled_payment() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_scheduled_payment(accounts(2), 5_000.into(), 100);
+        let subscription_index = contract.subscriptions.subscription_index;
 
-        Ok(())
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(60)
+            .build());
+        contract.admin_set_timestamp(subscription_index, 50);
+        assert_eq!(contract.get_subscription(subscription_index).unlock_at, 140);
+        testing_env!(context.block_timestamp(139).build());
+        assert_eq!(contract.pending_settlement(subscription_index).0, 0);
+        testing_env!(context.block_timestamp(200).build());
+        assert_eq!(contract.pending_settlement(subscription_index).0, 5_000);
     }
 
-    /// Calculate the current balance in sNEAR for the account
-    fn current_balance(&self, account_id: AccountId) -> U128 {
-        let mut balance = self.balances.get(&account_id).unwrap_or_default();
-        // All incoming where account is destination
-        let timestamp = env::block_timestamp();
+    #[test]
+    #[should_panic(expected = "Permission required")]
+    fn test_admin_set_timestamp_not_owner() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        contract.admin_set_timestamp(contract.subscriptions.subscription_index, 5);
+    }
 
-        // TODO Naming could be better here
-        let yoctos_per_second = |subscription: &Subscription| -> u128 {
-            let difference = timestamp.saturating_sub(subscription.timestamp);
-            (difference as u128).saturating_mul(subscription.flow)
-        };
+    #[test]
+    #[should_panic(expected = "timestamp must not be in the future")]
+    fn test_admin_set_timestamp_in_the_future() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.admin_set_timestamp(contract.subscriptions.subscription_index, 11);
+    }
 
-        self.subscriptions
-            .inputs
-            .get(&account_id)
-            .unwrap_or_default()
-            .iter()
-            .for_each(|subscription_index| {
-                if let Ok(subscription) = self.subscriptions.try_get(*subscription_index) {
-                    balance = balance.saturating_add(yoctos_per_second(&subscription));
-                }
-            });
+    #[test]
+    fn test_capped_subscription() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_capped_subscription(accounts(2), 100, U128(1_000));
+        let subscription_index = contract.subscriptions.subscription_index;
 
-        // All outgoing where account is source
-        self.subscriptions
-            .outputs
-            .get(&account_id)
-            .unwrap_or_default()
-            .iter()
-            .for_each(|subscription_index| {
-                if let Ok(subscription) = self.subscriptions.try_get(*subscription_index) {
-                    // TODO check here the reserve amount??  Maybe it won't matter but to be sure
-                    balance = balance.saturating_sub(yoctos_per_second(&subscription));
-                }
-            });
+        testing_env!(context.block_timestamp(15).build());
+        contract.settle_partial(subscription_index);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 500);
 
-        balance.into()
+        testing_env!(context.block_timestamp(100).build());
+        assert_eq!(contract.current_balance(accounts(2)).0, 500);
+        contract.settle_partial(subscription_index);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 1_000);
+        assert!(!contract.subscriptions.exists(subscription_index));
+        assert_eq!(contract.stats().active_subscriptions, 0);
     }
-}
 
-#[near_bindgen]
-impl FungibleTokenCore for Paystream {
-    #[payable]
-    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>) {
-        self.token.ft_transfer(receiver_id, amount, memo)
+    #[test]
+    fn test_completion_time() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(3), 100);
+        assert_eq!(
+            contract.completion_time(contract.subscriptions.subscription_index),
+            None
+        );
+        contract.create_capped_subscription(accounts(2), 100, U128(1_050));
+        let subscription_index = contract.subscriptions.subscription_index;
+        assert_eq!(contract.completion_time(subscription_index), Some(U64(21)));
+
+        testing_env!(context.block_timestamp(20).build());
+        assert_eq!(contract.current_balance(accounts(2)).0, 1_000);
+        testing_env!(context.block_timestamp(21).build());
+        assert_eq!(contract.current_balance(accounts(2)).0, 1_050);
+        testing_env!(context.block_timestamp(22).build());
+        assert_eq!(contract.current_balance(accounts(2)).0, 1_050);
     }
 
-    #[payable]
-    fn ft_transfer_call(
-        &mut self,
-        receiver_id: AccountId,
-        amount: U128,
-        memo: Option<String>,
-        msg: String,
-    ) -> PromiseOrValue<U128> {
-        self.token.ft_transfer_call(receiver_id, amount, memo, msg)
+    #[test]
+    fn test_completion_time_of_zero_flow() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_capped_subscription(accounts(2), 100, U128(1_050));
+        let subscription_index = contract.subscriptions.subscription_index;
+        contract.update_subscription(subscription_index, 0);
+        assert_eq!(contract.completion_time(subscription_index), None);
     }
 
-    fn ft_total_supply(&self) -> U128 {
-        self.token.ft_total_supply()
+    #[test]
+    fn test_balances_of() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.reserve = 0;
+        contract.balances.insert(&accounts(1), &1_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+
+        testing_env!(context.block_timestamp(20).build());
+        assert_eq!(
+            contract.balances_of(vec![accounts(2), accounts(3), accounts(1)]),
+            vec![U128(1_000), U128(0), U128(999_000)]
+        );
     }
 
-    fn ft_balance_of(&self, account_id: AccountId) -> U128 {
-        self.current_balance(account_id)
+    #[test]
+    #[should_panic(expected = "too many accounts")]
+    fn test_balances_of_too_many_accounts() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances_of(vec![accounts(1); MAX_BALANCE_QUERY + 1]);
     }
-}
 
-// Handlers for `FungibleTokenResolver`
-impl Paystream {
-    fn on_account_closed(&mut self, account_id: AccountId, balance: Balance) {
-        log!("Closed @{} with {}", account_id, balance);
+    #[test]
+    fn test_total_balance_of() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.reserve = 0;
+        contract.balances.insert(&accounts(1), &1_000_000);
+        contract.balances.insert(&accounts(2), &1_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.create_subscription(accounts(2), accounts(3), 50);
+
+        testing_env!(context.block_timestamp(20).build());
+        assert_eq!(
+            contract.total_balance_of(vec![accounts(2), accounts(3)]).0,
+            1_001_000
+        );
+        assert_eq!(
+            contract
+                .total_balance_of(vec![accounts(1), accounts(2), accounts(3)])
+                .0,
+            2_000_000
+        );
     }
 
-    fn on_tokens_burned(&mut self, account_id: AccountId, amount: Balance) {
-        log!("Account @{} burned {}", account_id, amount);
+    fn sign_create(contract: &mut Paystream, payload: &SignedCreate) -> Base64VecU8 {
+        let secret = ed25519_dalek::SecretKey::from_bytes(&[7; 32]).unwrap();
+        let public = ed25519_dalek::PublicKey::from(&secret);
+        let mut public_key = vec![CurveType::ED25519 as u8];
+        public_key.extend_from_slice(public.as_bytes());
+        contract.set_relay_key(PublicKey::try_from(public_key).unwrap());
+        let signature = ed25519_dalek::ExpandedSecretKey::from(&secret)
+            .sign(&payload.try_to_vec().unwrap(), &public);
+        Base64VecU8(signature.to_bytes().to_vec())
     }
-}
 
-#[near_bindgen]
-impl FungibleTokenResolver for Paystream {
-    #[private]
-    fn ft_resolve_transfer(
-        &mut self,
-        sender_id: AccountId,
-        receiver_id: AccountId,
-        amount: U128,
-    ) -> U128 {
-        let (used_amount, burned_amount) =
-            self.token
-                .internal_ft_resolve_transfer(&sender_id, receiver_id, amount);
-        if burned_amount > 0 {
-            self.on_tokens_burned(sender_id, burned_amount);
-        }
-        used_amount.into()
+    #[test]
+    fn test_create_subscription_signed() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        let payload = SignedCreate {
+            contract_id: accounts(0),
+            source: accounts(1),
+            destination: accounts(2),
+            flow: U128(100),
+            nonce: 1,
+        };
+        let signature = sign_create(&mut contract, &payload);
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        let subscription = contract.create_subscription_signed(payload, signature);
+        assert_eq!(subscription.source, accounts(1));
+        assert_eq!(subscription.destination, accounts(2));
+        assert_eq!(contract.relay_nonce(accounts(1)), 1);
     }
-}
 
-near_contract_standards::impl_fungible_token_storage!(Paystream, token, on_account_closed);
+    #[test]
+    #[should_panic(expected = "nonce already used")]
+    fn test_create_subscription_signed_replayed() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        let payload = SignedCreate {
+            contract_id: accounts(0),
+            source: accounts(1),
+            destination: accounts(2),
+            flow: U128(100),
+            nonce: 1,
+        };
+        let signature = sign_create(&mut contract, &payload);
 
-#[near_bindgen]
-impl FungibleTokenMetadataProvider for Paystream {
-    fn ft_metadata(&self) -> FungibleTokenMetadata {
-        self.metadata.get().unwrap()
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.create_subscription_signed(
+            SignedCreate {
+                contract_id: accounts(0),
+                source: accounts(1),
+                destination: accounts(2),
+                flow: U128(100),
+                nonce: 1,
+            },
+            signature.clone(),
+        );
+        contract.create_subscription_signed(payload, signature);
     }
-}
 
-#[cfg(all(test, not(target_arch = "wasm32")))]
-mod tests {
-    use super::*;
-    use near_contract_standards::fungible_token::core::FungibleTokenCore;
-    use near_sdk::test_utils::{accounts, VMContextBuilder};
-    use near_sdk::testing_env;
+    #[test]
+    #[should_panic(expected = "payload signed for another contract")]
+    fn test_create_subscription_signed_for_other_contract() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        let payload = SignedCreate {
+            contract_id: accounts(4),
+            source: accounts(1),
+            destination: accounts(2),
+            flow: U128(100),
+            nonce: 1,
+        };
+        let signature = sign_create(&mut contract, &payload);
 
-    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
-        let mut builder = VMContextBuilder::new();
-        builder
-            .current_account_id(accounts(0))
-            .signer_account_id(predecessor_account_id.clone())
-            .predecessor_account_id(predecessor_account_id);
-        builder
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.create_subscription_signed(payload, signature);
     }
 
     #[test]
-    fn test_new() {
+    #[should_panic(expected = "invalid signature")]
+    fn test_create_subscription_signed_tampered() {
         let mut context = get_context(accounts(1));
         testing_env!(context.build());
-        let contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
-        testing_env!(context.is_view(true).build());
-        assert_eq!(contract.ft_total_supply().0, 0);
-        assert_eq!(contract.ft_balance_of(accounts(1)).0, 0);
-        assert_eq!(contract.owner(), &accounts(0));
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        let mut payload = SignedCreate {
+            contract_id: accounts(0),
+            source: accounts(1),
+            destination: accounts(2),
+            flow: U128(100),
+            nonce: 1,
+        };
+        let signature = sign_create(&mut contract, &payload);
+        payload.flow = U128(1_000);
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.create_subscription_signed(payload, signature);
     }
 
     #[test]
-    #[should_panic(expected = "Permission required")]
-    fn test_signer_is_owner() {
+    fn test_scheduled_payment_unlocks_after_freeze() {
         let mut context = get_context(accounts(1));
-        Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_scheduled_payment(accounts(2), 5_000.into(), 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+
         testing_env!(context
-            .is_view(false)
-            .signer_account_id(accounts(0))
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(50)
             .build());
-        Paystream::required(&accounts(0));
-        Paystream::required(&accounts(1));
+        contract.freeze_accrual();
+        testing_env!(context.block_timestamp(200).build());
+        contract.unfreeze_accrual();
+        testing_env!(context.block_timestamp(249).build());
+        assert_eq!(contract.pending_settlement(subscription_index).0, 0);
+        testing_env!(context.block_timestamp(10_000).build());
+        assert_eq!(contract.pending_settlement(subscription_index).0, 5_000);
     }
 
     #[test]
-    #[should_panic(expected = "should be new owner")]
-    fn test_should_be_new_owner() {
+    fn test_no_accrual_while_frozen() {
         let mut context = get_context(accounts(1));
-        let mut paystream = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        testing_env!(context.block_timestamp(10).build());
+        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        let subscription_index = contract.subscriptions.subscription_index;
+
         testing_env!(context
-            .is_view(false)
-            .signer_account_id(accounts(0))
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(20)
             .build());
-        paystream.set_owner(accounts(1));
+        contract.freeze_accrual();
+        testing_env!(context.block_timestamp(50).build());
+        assert_eq!(contract.current_balance(accounts(2)).0, 1_000);
         testing_env!(context
-            .is_view(false)
-            .signer_account_id(accounts(1))
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(40)
             .build());
-        paystream.set_owner(accounts(1));
-    }
+        contract.settle_partial(subscription_index);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 1_000);
 
-    #[test]
-    #[should_panic(expected = "should be new treasurer")]
-    fn test_should_be_new_treasurer() {
-        let mut context = get_context(accounts(1));
-        let mut paystream = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
         testing_env!(context
-            .is_view(false)
-            .signer_account_id(accounts(0))
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(50)
             .build());
-        paystream.set_treasurer(accounts(1));
-        paystream.set_treasurer(accounts(1));
-    }
-
-    #[test]
-    #[should_panic(expected = "The contract is not initialized")]
-    fn test_default() {
-        let context = get_context(accounts(1));
-        testing_env!(context.build());
-        let _contract = Paystream::default();
+        contract.unfreeze_accrual();
+        assert_eq!(contract.current_balance(accounts(2)).0, 0);
+        testing_env!(context.block_timestamp(60).build());
+        assert_eq!(contract.current_balance(accounts(2)).0, 1_000);
+        assert_eq!(contract.get_subscription(subscription_index).timestamp, 50);
     }
 
     #[test]
-    #[should_panic(expected = "subscription not present")]
-    fn test_livecycle_of_subscription() {
+    fn test_accrual_frozen_twice() {
         let mut context = get_context(accounts(1));
-        let block_timestamp = 10;
-        let flow = 100;
-        testing_env!(context.block_timestamp(block_timestamp).build());
+        testing_env!(context.block_timestamp(10).build());
         let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
         contract.balances.insert(&accounts(1), &1_000_000_000);
-        let subscription = contract.create_subscription(accounts(1), accounts(2), flow);
-        assert_eq!(subscription.source, accounts(1));
-        assert_eq!(subscription.destination, accounts(2));
-        assert_eq!(subscription.flow, flow);
-        assert_eq!(subscription.timestamp, block_timestamp);
+        contract.create_subscription(accounts(1), accounts(2), 100);
 
-        let subscriptions = contract.subscriptions_by_account();
-        let new_subscription = contract.get_subscription(subscriptions[0]);
-        assert_eq!(
-            new_subscription, subscription,
-            "what is created isn't what is stored"
-        );
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(20)
+            .build());
+        contract.freeze_accrual();
+        testing_env!(context.block_timestamp(50).build());
+        contract.unfreeze_accrual();
 
-        let updated_subscription = contract.update_subscription(subscriptions[0], 200);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(60)
+            .build());
+        contract.create_subscription(accounts(1), accounts(3), 100);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(70)
+            .build());
+        contract.freeze_accrual();
+        testing_env!(context.block_timestamp(100).build());
+        contract.unfreeze_accrual();
+
+        testing_env!(context.block_timestamp(110).build());
+        assert_eq!(contract.current_balance(accounts(2)).0, 4_000);
+        assert_eq!(contract.current_balance(accounts(3)).0, 2_000);
         assert_eq!(
-            updated_subscription.flow, 200,
-            "rate should have been updated"
+            contract.current_balance(accounts(1)).0,
+            1_000_000_000 - 6_000
         );
-
-        contract.remove_subscription(subscriptions[0]);
-        contract.get_subscription(subscriptions[0]);
     }
 
     #[test]
-    #[should_panic(expected = "source must not be destination")]
-    fn test_should_fail_source_must_not_be_destination() {
-        let context = get_context(accounts(1));
-        testing_env!(context.build());
+    fn test_subscription_created_while_frozen() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.block_timestamp(100).build());
         let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
-        contract.create_subscription(accounts(1), accounts(1), 100);
-    }
+        contract.balances.insert(&accounts(1), &1_000_000_000);
+        contract.freeze_accrual();
 
-    #[test]
-    #[should_panic(expected = "rate needs to be greater than zero")]
-    fn test_rate_needs_to_be_greater_than_zero() {
-        let context = get_context(accounts(1));
-        testing_env!(context.build());
-        let mut contract = Paystream::new(accounts(0), WRAP_CONTRACT.parse().unwrap());
-        contract.create_subscription(accounts(1), accounts(2), 0);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(150)
+            .build());
+        contract.create_subscription(accounts(1), accounts(2), 100);
+        assert_eq!(contract.current_balance(accounts(2)).0, 0);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(200)
+            .build());
+        contract.unfreeze_accrual();
+
+        testing_env!(context.block_timestamp(300).build());
+        assert_eq!(contract.current_balance(accounts(2)).0, 10_000);
+        assert_eq!(
+            contract.current_balance(accounts(1)).0,
+            1_000_000_000 - 10_000
+        );
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.settle_partial(contract.subscriptions.subscription_index);
+        assert_eq!(contract.claimable_of(accounts(2)).0, 10_000);
     }
 }